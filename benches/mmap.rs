@@ -0,0 +1,57 @@
+//! Compares reading a large input through a memory map against buffered file reads.
+//! Run with `cargo bench --bench mmap`.
+
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use csv_payments::config::Config;
+use csv_payments::process_csv;
+
+const ROWS: u32 = 1_000_000;
+const RUNS: u32 = 5;
+
+fn time_reads(args: &[String]) -> Duration {
+    let config = Config::new(args);
+    let mut fastest = Duration::MAX;
+
+    for _ in 0..RUNS {
+        let mut warnings = Vec::new();
+        let start = Instant::now();
+
+        let txs = process_csv(&config, &mut warnings).expect("input should be read");
+
+        fastest = fastest.min(start.elapsed());
+        assert_eq!(txs.len(), ROWS as usize);
+    }
+
+    fastest
+}
+
+fn main() {
+    let path = env::temp_dir().join("csv-payments-bench.csv");
+
+    let mut out = BufWriter::new(File::create(&path).expect("input file should be created"));
+    writeln!(out, "type,client,tx,amount").expect("header should be written");
+
+    for tx_id in 0..ROWS {
+        writeln!(
+            out,
+            "deposit,{},{tx_id},{}.{:04}",
+            tx_id % 1000,
+            tx_id % 500,
+            tx_id % 10_000
+        )
+        .expect("row should be written");
+    }
+
+    out.flush().expect("input file should be flushed");
+
+    let path = path.to_string_lossy().to_string();
+    let buffered = time_reads(&["csv-payments".to_string(), path.clone()]);
+    let mapped = time_reads(&["csv-payments".to_string(), "--mmap".to_string(), path]);
+
+    println!("buffered reads: {buffered:?}");
+    println!("mmap reads:     {mapped:?}");
+}
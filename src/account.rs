@@ -1,50 +1,103 @@
+use std::collections::HashSet;
 use std::fmt::Display;
 
 use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use crate::amount::Amount;
+use crate::error::PaymentError;
 use crate::transaction::{Transaction, TransactionType};
 
 #[derive(Debug, Deserialize, Serialize)]
-pub struct Account {
+#[serde(bound(deserialize = "A: Amount + Deserialize<'de>"))]
+pub struct Account<A = Decimal> {
     #[serde(rename = "client")]
     pub client_id: u16,
     #[serde(rename = "available")]
-    pub funds_available: Decimal,
+    pub funds_available: A,
     #[serde(rename = "held")]
-    pub funds_held: Decimal,
+    pub funds_held: A,
+    /// Authorized funds which haven't been captured yet, only written by the `pending` profile and
+    /// read back when the column is present
+    #[serde(rename = "pending", default = "A::zero", skip_serializing)]
+    pub funds_pending: A,
     #[serde(rename = "total")]
-    pub funds_total: Decimal,
+    pub funds_total: A,
     pub locked: bool,
+    /// Transactions whose chargebacks locked this account and haven't been reversed
+    #[serde(skip)]
+    pub chargebacks: HashSet<u32>,
+    /// Whether any deposit, withdrawal or capture was applied, as opposed to only rejected
+    #[serde(skip)]
+    pub had_successful_activity: bool,
 }
 
-impl Account {
-    pub fn new(id: u16) -> Account {
+impl<A: Amount> Account<A> {
+    pub fn new(id: u16) -> Account<A> {
         Account {
             client_id: id,
-            funds_available: Decimal::new(0, 0),
-            funds_held: Decimal::new(0, 0),
-            funds_total: Decimal::new(0, 0),
+            funds_available: A::zero(),
+            funds_held: A::zero(),
+            funds_pending: A::zero(),
+            funds_total: A::zero(),
             locked: false,
+            chargebacks: HashSet::new(),
+            had_successful_activity: false,
         }
     }
 
-    pub fn settle_transaction(&mut self, tx: &Transaction, ref_tx: Option<&Transaction>) {
+    /// Whether any of the account's balances has gone below zero
+    pub fn has_negative_balance(&self) -> bool {
+        [
+            self.funds_available,
+            self.funds_held,
+            self.funds_pending,
+            self.funds_total,
+        ]
+        .iter()
+        .any(|balance| *balance < A::zero())
+    }
+
+    /// Adds the balances of another ledger's account for the same client to this one
+    pub fn merge(&mut self, other: Account<A>) {
+        self.funds_available += other.funds_available;
+        self.funds_held += other.funds_held;
+        self.funds_pending += other.funds_pending;
+        self.funds_total += other.funds_total;
+        self.chargebacks.extend(other.chargebacks);
+        self.locked = self.locked || other.locked;
+        self.had_successful_activity =
+            self.had_successful_activity || other.had_successful_activity;
+    }
+
+    /// Rounds every balance to `dp` decimal places, rounding midpoints to even. The total is set
+    /// to the rounded available and held funds, so it still adds up.
+    pub fn round_balances(&mut self, dp: u32) {
+        for balance in [
+            &mut self.funds_available,
+            &mut self.funds_held,
+            &mut self.funds_pending,
+        ] {
+            *balance = A::from_decimal(balance.to_decimal().round_dp(dp));
+        }
+
+        self.funds_total = self.funds_available + self.funds_held;
+    }
+
+    pub fn settle_transaction(
+        &mut self,
+        tx: &Transaction<A>,
+        ref_tx: Option<&Transaction<A>>,
+    ) -> Result<(), PaymentError> {
         match tx.r#type {
             TransactionType::Deposit => {
                 if let Some(tx_amount) = tx.amount {
                     self.funds_available += tx_amount;
                     self.funds_total += tx_amount;
+                    self.had_successful_activity = true;
                 }
             }
-            TransactionType::Withdrawal => {
-                if let Some(tx_amount) = tx.amount {
-                    if self.funds_available >= tx_amount {
-                        self.funds_available -= tx_amount;
-                        self.funds_total -= tx_amount;
-                    }
-                }
-            }
+            TransactionType::Withdrawal => return self.settle_withdrawal(tx, false),
             TransactionType::Dispute => {
                 if let Some(ref_tx) = ref_tx {
                     if let Some(tx_amount) = ref_tx.amount {
@@ -66,19 +119,79 @@ impl Account {
                     if let Some(tx_amount) = ref_tx.amount {
                         self.funds_held -= tx_amount;
                         self.funds_total -= tx_amount;
+                        self.chargebacks.insert(ref_tx.tx_id);
                         self.locked = true;
                     }
                 }
             }
+            TransactionType::Reversal => {
+                if let Some(ref_tx) = ref_tx {
+                    if let Some(tx_amount) = ref_tx.amount {
+                        self.funds_available += tx_amount;
+                        self.funds_total += tx_amount;
+                        self.chargebacks.remove(&ref_tx.tx_id);
+                        self.locked = !self.chargebacks.is_empty();
+                    }
+                }
+            }
+            TransactionType::Authorize => {
+                if let Some(tx_amount) = tx.amount {
+                    self.funds_pending += tx_amount;
+                }
+            }
+            TransactionType::Capture => {
+                if let Some(ref_tx) = ref_tx {
+                    if let Some(tx_amount) = ref_tx.amount {
+                        self.funds_pending -= tx_amount;
+                        self.funds_available += tx_amount;
+                        self.funds_total += tx_amount;
+                        self.had_successful_activity = true;
+                    }
+                }
+            }
+            TransactionType::Expire => {
+                if let Some(ref_tx) = ref_tx {
+                    if let Some(tx_amount) = ref_tx.amount {
+                        self.funds_pending -= tx_amount;
+                    }
+                }
+            }
         }
+
+        Ok(())
+    }
+
+    /// Withdraws from available funds, and when `from_held` is set, from held funds once available
+    /// funds run out. Withdrawals which can't be covered are rejected.
+    pub fn settle_withdrawal(
+        &mut self,
+        tx: &Transaction<A>,
+        from_held: bool,
+    ) -> Result<(), PaymentError> {
+        if let Some(tx_amount) = tx.amount {
+            if self.funds_available >= tx_amount {
+                self.funds_available -= tx_amount;
+            } else if from_held && self.funds_available + self.funds_held >= tx_amount {
+                self.funds_held -= tx_amount - self.funds_available;
+                self.funds_available = A::zero();
+            } else {
+                return Err(PaymentError::InsufficientFunds { tx_id: tx.tx_id });
+            }
+
+            self.funds_total -= tx_amount;
+            self.had_successful_activity = true;
+        }
+
+        Ok(())
     }
 }
 
-impl Display for Account {
+impl<A: Amount> Display for Account<A> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "id: {}", &self.client_id).unwrap_or(());
         writeln!(f, "funds available: {}", &self.funds_available).unwrap_or(());
         writeln!(f, "funds held: {}", &self.funds_held).unwrap_or(());
+        writeln!(f, "funds pending: {}", &self.funds_pending).unwrap_or(());
         writeln!(f, "funds total: {}", &self.funds_total).unwrap_or(());
         writeln!(f, "locked: {}", &self.locked).unwrap_or(());
 
@@ -88,10 +201,13 @@ impl Display for Account {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+
     use rust_decimal::Decimal;
 
     use crate::{
         account::Account,
+        error::PaymentError,
         transaction::{Transaction, TransactionType},
     };
 
@@ -102,11 +218,12 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: Some(Decimal::new(1_05, 2)),
+            timestamp: None,
         };
 
         let mut acc = Account::new(tx.client_id);
 
-        acc.settle_transaction(&tx, None);
+        assert_eq!(acc.settle_transaction(&tx, None), Ok(()));
 
         assert_eq!(acc.funds_available, Decimal::new(1_05, 2));
         assert_eq!(acc.funds_total, Decimal::new(1_05, 2));
@@ -119,17 +236,21 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: Some(Decimal::new(1_05, 2)),
+            timestamp: None,
         };
 
         let mut acc = Account {
             client_id: tx.client_id,
             funds_available: Decimal::new(3_05, 2),
             funds_held: Decimal::new(0, 0),
+            funds_pending: Decimal::new(0, 0),
             funds_total: Decimal::new(3_05, 2),
             locked: false,
+            chargebacks: HashSet::new(),
+            had_successful_activity: true,
         };
 
-        acc.settle_transaction(&tx, None);
+        assert_eq!(acc.settle_transaction(&tx, None), Ok(()));
 
         assert_eq!(acc.funds_available, Decimal::new(2_00, 2));
         assert_eq!(acc.funds_total, Decimal::new(2_00, 2));
@@ -139,7 +260,10 @@ mod tests {
         acc.funds_held = Decimal::new(3_05, 2);
         acc.funds_total = Decimal::new(3_05, 2);
 
-        acc.settle_transaction(&tx, None);
+        assert_eq!(
+            acc.settle_transaction(&tx, None),
+            Err(PaymentError::InsufficientFunds { tx_id: 1 })
+        );
 
         assert_eq!(acc.funds_available, Decimal::new(0, 0));
         assert_eq!(acc.funds_total, Decimal::new(3_05, 2));
@@ -152,6 +276,7 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: Some(Decimal::new(500, 0)),
+            timestamp: None,
         };
 
         let dispute_tx = Transaction {
@@ -159,16 +284,20 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: None,
+            timestamp: None,
         };
 
         let mut acc = Account::new(deposit_tx.client_id);
 
-        acc.settle_transaction(&deposit_tx, None);
+        assert_eq!(acc.settle_transaction(&deposit_tx, None), Ok(()));
 
         assert_eq!(acc.funds_available, Decimal::new(500, 0));
         assert_eq!(acc.funds_total, Decimal::new(500, 0));
 
-        acc.settle_transaction(&dispute_tx, Some(&deposit_tx));
+        assert_eq!(
+            acc.settle_transaction(&dispute_tx, Some(&deposit_tx)),
+            Ok(())
+        );
 
         assert_eq!(acc.funds_available, Decimal::new(0, 0));
         assert_eq!(acc.funds_held, Decimal::new(500, 0));
@@ -182,6 +311,7 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: Some(Decimal::new(500, 0)),
+            timestamp: None,
         };
 
         let dispute_tx = Transaction {
@@ -189,6 +319,7 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: None,
+            timestamp: None,
         };
 
         let resolve_tx = Transaction {
@@ -196,22 +327,29 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: None,
+            timestamp: None,
         };
 
         let mut acc = Account::new(deposit_tx.client_id);
 
-        acc.settle_transaction(&deposit_tx, None);
+        assert_eq!(acc.settle_transaction(&deposit_tx, None), Ok(()));
 
         assert_eq!(acc.funds_available, Decimal::new(500, 0));
         assert_eq!(acc.funds_total, Decimal::new(500, 0));
 
-        acc.settle_transaction(&dispute_tx, Some(&deposit_tx));
+        assert_eq!(
+            acc.settle_transaction(&dispute_tx, Some(&deposit_tx)),
+            Ok(())
+        );
 
         assert_eq!(acc.funds_available, Decimal::new(0, 0));
         assert_eq!(acc.funds_held, Decimal::new(500, 0));
         assert_eq!(acc.funds_total, Decimal::new(500, 0));
 
-        acc.settle_transaction(&resolve_tx, Some(&deposit_tx));
+        assert_eq!(
+            acc.settle_transaction(&resolve_tx, Some(&deposit_tx)),
+            Ok(())
+        );
 
         assert_eq!(acc.funds_available, Decimal::new(500, 0));
         assert_eq!(acc.funds_held, Decimal::new(0, 0));
@@ -225,6 +363,7 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: Some(Decimal::new(500, 0)),
+            timestamp: None,
         };
 
         let dispute_tx = Transaction {
@@ -232,6 +371,7 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: None,
+            timestamp: None,
         };
 
         let chargeback_tx = Transaction {
@@ -239,26 +379,231 @@ mod tests {
             client_id: 1,
             tx_id: 1,
             amount: None,
+            timestamp: None,
         };
 
         let mut acc = Account::new(deposit_tx.client_id);
 
-        acc.settle_transaction(&deposit_tx, None);
+        assert_eq!(acc.settle_transaction(&deposit_tx, None), Ok(()));
 
         assert_eq!(acc.funds_available, Decimal::new(500, 0));
         assert_eq!(acc.funds_total, Decimal::new(500, 0));
 
-        acc.settle_transaction(&dispute_tx, Some(&deposit_tx));
+        assert_eq!(
+            acc.settle_transaction(&dispute_tx, Some(&deposit_tx)),
+            Ok(())
+        );
 
         assert_eq!(acc.funds_available, Decimal::new(0, 0));
         assert_eq!(acc.funds_held, Decimal::new(500, 0));
         assert_eq!(acc.funds_total, Decimal::new(500, 0));
 
-        acc.settle_transaction(&chargeback_tx, Some(&deposit_tx));
+        assert_eq!(
+            acc.settle_transaction(&chargeback_tx, Some(&deposit_tx)),
+            Ok(())
+        );
+
+        assert_eq!(acc.funds_available, Decimal::new(0, 0));
+        assert_eq!(acc.funds_held, Decimal::new(0, 0));
+        assert_eq!(acc.funds_total, Decimal::new(0, 0));
+        assert!(acc.locked);
+    }
+
+    #[test]
+    fn settle_reversal_transaction() {
+        let deposit1_tx = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::new(500, 0)),
+            timestamp: None,
+        };
+
+        let deposit2_tx = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 2,
+            amount: Some(Decimal::new(200, 0)),
+            timestamp: None,
+        };
+
+        let mut acc = Account::new(deposit1_tx.client_id);
+
+        assert_eq!(acc.settle_transaction(&deposit1_tx, None), Ok(()));
+        assert_eq!(acc.settle_transaction(&deposit2_tx, None), Ok(()));
+
+        for ref_tx in [&deposit1_tx, &deposit2_tx] {
+            let dispute_tx = Transaction {
+                r#type: TransactionType::Dispute,
+                amount: None,
+                ..*ref_tx
+            };
+
+            let chargeback_tx = Transaction {
+                r#type: TransactionType::Chargeback,
+                amount: None,
+                ..*ref_tx
+            };
+
+            assert_eq!(acc.settle_transaction(&dispute_tx, Some(ref_tx)), Ok(()));
+            assert_eq!(acc.settle_transaction(&chargeback_tx, Some(ref_tx)), Ok(()));
+        }
+
+        assert_eq!(acc.funds_available, Decimal::new(0, 0));
+        assert_eq!(acc.funds_total, Decimal::new(0, 0));
+        assert!(acc.locked);
+
+        let reversal1_tx = Transaction {
+            r#type: TransactionType::Reversal,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            timestamp: None,
+        };
+
+        assert_eq!(
+            acc.settle_transaction(&reversal1_tx, Some(&deposit1_tx)),
+            Ok(())
+        );
+
+        // The chargeback of tx 2 still keeps the account locked
+        assert_eq!(acc.funds_available, Decimal::new(500, 0));
+        assert_eq!(acc.funds_total, Decimal::new(500, 0));
+        assert!(acc.locked);
+
+        let reversal2_tx = Transaction {
+            r#type: TransactionType::Reversal,
+            client_id: 1,
+            tx_id: 2,
+            amount: None,
+            timestamp: None,
+        };
+
+        assert_eq!(
+            acc.settle_transaction(&reversal2_tx, Some(&deposit2_tx)),
+            Ok(())
+        );
+
+        assert_eq!(acc.funds_available, Decimal::new(700, 0));
+        assert_eq!(acc.funds_held, Decimal::new(0, 0));
+        assert_eq!(acc.funds_total, Decimal::new(700, 0));
+        assert!(!acc.locked);
+    }
+
+    #[test]
+    fn settle_authorize_capture_transaction() {
+        let authorize_tx = Transaction {
+            r#type: TransactionType::Authorize,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::new(250, 0)),
+            timestamp: None,
+        };
+
+        let capture_tx = Transaction {
+            r#type: TransactionType::Capture,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            timestamp: None,
+        };
+
+        let mut acc = Account::new(authorize_tx.client_id);
+
+        assert_eq!(acc.settle_transaction(&authorize_tx, None), Ok(()));
+
+        assert_eq!(acc.funds_available, Decimal::new(0, 0));
+        assert_eq!(acc.funds_pending, Decimal::new(250, 0));
+        assert_eq!(acc.funds_total, Decimal::new(0, 0));
+
+        assert_eq!(
+            acc.settle_transaction(&capture_tx, Some(&authorize_tx)),
+            Ok(())
+        );
+
+        assert_eq!(acc.funds_available, Decimal::new(250, 0));
+        assert_eq!(acc.funds_pending, Decimal::new(0, 0));
+        assert_eq!(acc.funds_total, Decimal::new(250, 0));
+    }
+
+    #[test]
+    fn settle_authorize_expire_transaction() {
+        let authorize_tx = Transaction {
+            r#type: TransactionType::Authorize,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::new(250, 0)),
+            timestamp: None,
+        };
+
+        let withdrawal_tx = Transaction {
+            r#type: TransactionType::Withdrawal,
+            client_id: 1,
+            tx_id: 2,
+            amount: Some(Decimal::new(100, 0)),
+            timestamp: None,
+        };
+
+        let expire_tx = Transaction {
+            r#type: TransactionType::Expire,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            timestamp: None,
+        };
+
+        let mut acc = Account::new(authorize_tx.client_id);
+
+        assert_eq!(acc.settle_transaction(&authorize_tx, None), Ok(()));
+
+        // Pending funds aren't spendable until they're captured
+        assert_eq!(
+            acc.settle_transaction(&withdrawal_tx, None),
+            Err(PaymentError::InsufficientFunds { tx_id: 2 })
+        );
+
+        assert_eq!(acc.funds_pending, Decimal::new(250, 0));
+
+        // The authorization is dropped without ever being captured
+        assert_eq!(
+            acc.settle_transaction(&expire_tx, Some(&authorize_tx)),
+            Ok(())
+        );
 
         assert_eq!(acc.funds_available, Decimal::new(0, 0));
         assert_eq!(acc.funds_held, Decimal::new(0, 0));
+        assert_eq!(acc.funds_pending, Decimal::new(0, 0));
         assert_eq!(acc.funds_total, Decimal::new(0, 0));
-        assert_eq!(acc.locked, true);
+    }
+
+    #[test]
+    fn settle_withdrawal_from_held_funds() {
+        let withdrawal_tx = Transaction {
+            r#type: TransactionType::Withdrawal,
+            client_id: 1,
+            tx_id: 3,
+            amount: Some(Decimal::new(5, 0)),
+            timestamp: None,
+        };
+
+        let mut acc = Account::new(withdrawal_tx.client_id);
+        acc.funds_available = Decimal::new(2, 0);
+        acc.funds_held = Decimal::new(4, 0);
+        acc.funds_total = Decimal::new(6, 0);
+
+        assert_eq!(
+            acc.settle_withdrawal(&withdrawal_tx, false),
+            Err(PaymentError::InsufficientFunds { tx_id: 3 })
+        );
+
+        assert_eq!(acc.funds_available, Decimal::new(2, 0));
+        assert_eq!(acc.funds_held, Decimal::new(4, 0));
+        assert_eq!(acc.funds_total, Decimal::new(6, 0));
+
+        assert_eq!(acc.settle_withdrawal(&withdrawal_tx, true), Ok(()));
+
+        assert_eq!(acc.funds_available, Decimal::new(0, 0));
+        assert_eq!(acc.funds_held, Decimal::new(1, 0));
+        assert_eq!(acc.funds_total, Decimal::new(1, 0));
     }
 }
@@ -0,0 +1,82 @@
+use std::fmt::{Debug, Display};
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+
+use rust_decimal::prelude::*;
+use serde::Serialize;
+
+/// Arithmetic needed to settle balances, allowing the numeric backend to be swapped out
+pub trait Amount:
+    Copy
+    + Debug
+    + Display
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + AddAssign
+    + SubAssign
+    + Serialize
+{
+    fn zero() -> Self;
+
+    /// Converts an amount parsed from the input, which is always read as a `Decimal`
+    fn from_decimal(value: Decimal) -> Self;
+
+    fn to_decimal(self) -> Decimal;
+}
+
+impl Amount for Decimal {
+    fn zero() -> Self {
+        Decimal::new(0, 0)
+    }
+
+    fn from_decimal(value: Decimal) -> Self {
+        value
+    }
+
+    fn to_decimal(self) -> Decimal {
+        self
+    }
+}
+
+/// Trades exactness for speed, amounts may pick up rounding errors
+impl Amount for f64 {
+    fn zero() -> Self {
+        0.0
+    }
+
+    fn from_decimal(value: Decimal) -> Self {
+        value.to_f64().unwrap_or(f64::NAN)
+    }
+
+    fn to_decimal(self) -> Decimal {
+        Decimal::from_f64(self).unwrap_or_default()
+    }
+}
+
+/// The amount type used when processing input files, `Decimal` unless the `f64-balances` feature
+/// is enabled
+#[cfg(not(feature = "f64-balances"))]
+pub type Balance = Decimal;
+#[cfg(feature = "f64-balances")]
+pub type Balance = f64;
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::amount::Amount;
+
+    #[test]
+    fn decimal_amounts_stay_exact() {
+        let amount = Decimal::new(500_0005, 4);
+
+        assert_eq!(Decimal::from_decimal(amount), amount);
+        assert_eq!(amount.to_decimal(), amount);
+
+        let mut total = Decimal::zero();
+        total += Decimal::new(1, 1);
+        total += Decimal::new(2, 1);
+
+        assert_eq!(total, Decimal::new(3, 1));
+    }
+}
@@ -0,0 +1,220 @@
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use csv::WriterBuilder;
+
+use crate::amount::Amount;
+use crate::config::{Config, InputFormat};
+use crate::error::PaymentError;
+use crate::ledger::Ledger;
+
+/// Flags naming a file the run reads, with the name its copy gets in the bundle
+const INPUT_FILES: [(&str, &str); 3] = [
+    ("--import-state", "import-state.csv"),
+    ("--import-refs", "import-refs.csv"),
+    ("--resume", "resume.json"),
+];
+
+/// Flags left out of the bundled arguments with how many values each takes. A `--config` file's
+/// flags are already inlined and the others only decide where the run writes.
+const DROPPED_FLAGS: [(&str, usize); 7] = [
+    ("--config", 1),
+    ("--bundle", 1),
+    ("--dump-refs", 1),
+    ("--quarantine", 1),
+    ("--balance-series", 2),
+    ("--save-state", 1),
+    ("--save-state-every", 1),
+];
+
+/// Writes everything needed to reproduce a run into `dir`: copies of the input and every other
+/// file the run read, the arguments of the run, the resulting accounts in client order and every
+/// warning and rejection
+///
+/// `args.txt` holds one argument per line, with a `--config` file's flags inlined. It points at
+/// the copies inside the bundle and leaves out flags which write files, apart from a Parquet
+/// output written into the bundle, so running with it from `dir` reproduces the accounts without
+/// writing anywhere else.
+pub fn write_bundle<A: Amount>(
+    dir: &Path,
+    config: &Config,
+    ledger: &Ledger<A>,
+    warnings: &[PaymentError],
+) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+
+    let mut bundle_args = Vec::new();
+    let mut args = config.args.iter();
+
+    while let Some(arg) = args.next() {
+        if let Some((_, values)) = DROPPED_FLAGS.iter().find(|(flag, _)| flag == arg) {
+            args.nth(values - 1);
+            continue;
+        }
+
+        if let Some((_, name)) = INPUT_FILES.iter().find(|(flag, _)| flag == arg) {
+            if let Some(path) = args.next() {
+                fs::copy(path, dir.join(name))?;
+                bundle_args.extend([arg.clone(), name.to_string()]);
+            }
+            continue;
+        }
+
+        match arg.as_str() {
+            "--format" => {
+                bundle_args.push(arg.clone());
+
+                if let Some(format) = args.next() {
+                    bundle_args.push(format.clone());
+
+                    if format == "parquet" {
+                        args.next();
+                        bundle_args.push("output.parquet".to_string());
+                    }
+                }
+            }
+            _ if *arg == config.transactions_path => {}
+            _ => bundle_args.push(arg.clone()),
+        }
+    }
+
+    let path = &config.transactions_path;
+    let extension = match config.input_format {
+        InputFormat::Csv => "csv",
+        InputFormat::Json => "json",
+    };
+    let input_name = if path.ends_with(".gz") {
+        format!("input.{extension}.gz")
+    } else {
+        format!("input.{extension}")
+    };
+
+    fs::copy(path, dir.join(&input_name))?;
+    bundle_args.push(input_name);
+
+    let mut args_file = String::new();
+
+    for arg in bundle_args {
+        writeln!(args_file, "{arg}")?;
+    }
+
+    fs::write(dir.join("args.txt"), args_file)?;
+
+    let mut writer = WriterBuilder::new().from_path(dir.join("accounts.csv"))?;
+
+    for (_, acc) in ledger.iter_sorted() {
+        writer.serialize(acc)?;
+    }
+
+    writer.flush()?;
+
+    let mut report = String::new();
+
+    for e in warnings.iter().chain(&ledger.warnings) {
+        writeln!(report, "Warning: {e}")?;
+    }
+
+    for e in &ledger.errors {
+        writeln!(report, "Rejected transaction: {e}")?;
+    }
+
+    fs::write(dir.join("errors.txt"), report)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use crate::{
+        bundle::write_bundle, config::Config, ledger::Ledger, process_csv, process_ledger,
+        read_accounts, tests::args_from,
+    };
+
+    #[test]
+    fn write_reproducible_bundle() {
+        let dir = env::temp_dir().join("csv-payments-bundle");
+        let input = env::temp_dir().join("csv-payments-bundle-input.json");
+        let state = env::temp_dir().join("csv-payments-bundle-state.csv");
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::write(
+            &input,
+            r#"[
+                {"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"},
+                {"type": "withdrawal", "client": 1, "tx": 2, "amount": "5.25"}
+            ]"#,
+        )
+        .expect("input file should be written");
+        fs::write(
+            &state,
+            "client,available,held,total,locked\n2,1,0,1,false\n",
+        )
+        .expect("state file should be written");
+
+        let dir_arg = dir.to_string_lossy().to_string();
+        let input_arg = input.to_string_lossy().to_string();
+        let state_arg = state.to_string_lossy().to_string();
+        let config = Config::new(&args_from(&[
+            "csv-payments",
+            "--input-format",
+            "json",
+            "--import-state",
+            &state_arg,
+            "--dump-refs",
+            "refs.csv",
+            "--bundle",
+            &dir_arg,
+            &input_arg,
+        ]));
+        let mut warnings = Vec::new();
+
+        let mut ledger = Ledger::new();
+        ledger.import_accounts(
+            read_accounts(fs::File::open(&state).expect("state file should open"))
+                .expect("state should be read"),
+        );
+
+        let txs = process_csv(&config, &mut warnings).expect("input should be read");
+        let ledger = process_ledger(ledger, txs);
+
+        write_bundle(&dir, &config, &ledger, &warnings).expect("bundle should be written");
+
+        let mut files: Vec<_> = fs::read_dir(&dir)
+            .expect("bundle directory should exist")
+            .map(|entry| {
+                entry
+                    .expect("bundle entry should be readable")
+                    .file_name()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        files.sort();
+
+        assert_eq!(
+            files,
+            [
+                "accounts.csv",
+                "args.txt",
+                "errors.txt",
+                "import-state.csv",
+                "input.json"
+            ]
+        );
+
+        // The replayed run reads the copies and doesn't dump refs outside the bundle
+        assert_eq!(
+            fs::read_to_string(dir.join("args.txt")).expect("arguments should be readable"),
+            "--input-format\njson\n--import-state\nimport-state.csv\ninput.json\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("errors.txt")).expect("error report should be readable"),
+            "Rejected transaction: transaction 2 withdraws more than the account's funds\n"
+        );
+    }
+}
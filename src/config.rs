@@ -1,15 +1,414 @@
+use std::fs;
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use toml::Value;
+
+use crate::ledger::SettlementPolicy;
+use crate::profile::{find_profile, OutputProfile};
+use crate::transaction::{TransactionType, TypeCodes};
+
+/// How rows with an empty `client` field are handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmptyClientPolicy {
+    /// Drop the row and report it as a warning
+    Skip,
+    /// Abort processing
+    Error,
+}
+
+impl FromStr for EmptyClientPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(EmptyClientPolicy::Skip),
+            "error" => Ok(EmptyClientPolicy::Error),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the input is encoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    /// A JSON array of objects with the same fields as the CSV columns
+    Json,
+}
+
+impl FromStr for InputFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "json" => Ok(InputFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Schema version of the input, deciding which columns each row must have
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputVersion {
+    /// `type, client, tx, amount`
+    V1,
+    /// Adds a `currency` column
+    V2,
+}
+
+impl InputVersion {
+    pub fn columns(self) -> &'static [&'static str] {
+        match self {
+            InputVersion::V1 => &["type", "client", "tx", "amount"],
+            InputVersion::V2 => &["type", "client", "tx", "amount", "currency"],
+        }
+    }
+}
+
+impl FromStr for InputVersion {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(InputVersion::V1),
+            "2" => Ok(InputVersion::V2),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where and how the finalized accounts are written
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputFormat {
+    /// CSV on stdout
+    Csv,
+    /// A Parquet file at the given path
+    #[cfg(feature = "parquet")]
+    Parquet(String),
+}
+
+#[derive(Debug)]
 pub struct Config {
     pub transactions_path: String,
+    pub flush_every: Option<usize>,
+    pub empty_client: EmptyClientPolicy,
+    pub dump_refs_path: Option<String>,
+    pub max_input_scale: Option<u32>,
+    pub detect_duplicate_file: bool,
+    pub require_disputes_resolved: bool,
+    pub output_profile: Option<&'static OutputProfile>,
+    pub policy: SettlementPolicy,
+    pub output_format: OutputFormat,
+    pub type_codes: TypeCodes,
+    pub quarantine_path: Option<String>,
+    pub input_version: InputVersion,
+    pub round_output: Option<u32>,
+    pub rounding_report: bool,
+    pub mmap: bool,
+    pub balance_series: Option<(u16, String)>,
+    pub tolerate_broken_pipe: bool,
+    pub verify_conservation: bool,
+    pub bundle_dir: Option<String>,
+    pub require_activity: bool,
+    pub input_format: InputFormat,
+    pub profile_latency: bool,
+    pub as_of: Option<u64>,
+    pub import_state_path: Option<String>,
+    pub import_refs_path: Option<String>,
+    pub result_hash: bool,
+    pub save_state_path: Option<String>,
+    pub save_state_every: Option<usize>,
+    pub resume_path: Option<String>,
+    /// Every argument after the program name, preceded by the flags of a `--config` file
+    pub args: Vec<String>,
 }
 
 impl Config {
     pub fn new(args: &[String]) -> Config {
-        if args.len() < 2 {
-            panic!("No transactions file provided, please specify a transaction file.")
+        // Flags from a config file go first, so the same flags on the command line override them
+        let (file_transactions_path, args) = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => {
+                let path: String = parse_value("--config", args.get(i + 1));
+                let (file_transactions_path, file_args) = config_file_args(&path);
+
+                let args = args
+                    .iter()
+                    .take(1)
+                    .cloned()
+                    .chain(file_args)
+                    .chain(args.iter().skip(1).cloned())
+                    .collect();
+
+                (file_transactions_path, args)
+            }
+            None => (None, args.to_vec()),
+        };
+
+        let mut transactions_path = None;
+        let mut flush_every = None;
+        let mut empty_client = EmptyClientPolicy::Error;
+        let mut dump_refs_path = None;
+        let mut max_input_scale = None;
+        let mut detect_duplicate_file = false;
+        let mut require_disputes_resolved = false;
+        let mut output_profile = None;
+        let mut policy = SettlementPolicy::default();
+        let mut output_format = OutputFormat::Csv;
+        let mut type_codes = TypeCodes::new();
+        let mut quarantine_path = None;
+        let mut input_version = InputVersion::V1;
+        let mut round_output = None;
+        let mut rounding_report = false;
+        let mut mmap = false;
+        let mut balance_series = None;
+        let mut tolerate_broken_pipe = false;
+        let mut verify_conservation = false;
+        let mut bundle_dir = None;
+        let mut require_activity = false;
+        let mut input_format = InputFormat::Csv;
+        let mut profile_latency = false;
+        let mut as_of = None;
+
+        let mut import_state_path = None;
+        let mut import_refs_path = None;
+        let mut result_hash = false;
+        let mut save_state_path = None;
+        let mut save_state_every = None;
+        let mut resume_path = None;
+
+        let given_args: Vec<String> = args.iter().skip(1).cloned().collect();
+        let mut args = given_args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--flush-every" => {
+                    let rows = parse_value(arg, args.next());
+
+                    if rows == 0 {
+                        panic!("--flush-every must be greater than 0.")
+                    }
+
+                    flush_every = Some(rows);
+                }
+                "--empty-client" => empty_client = parse_value(arg, args.next()),
+                "--dump-refs" => dump_refs_path = Some(parse_value(arg, args.next())),
+                "--max-input-scale" => max_input_scale = Some(parse_value(arg, args.next())),
+                "--detect-full-duplicate-file" => detect_duplicate_file = true,
+                "--require-disputes-resolved" => require_disputes_resolved = true,
+                "--profile" => {
+                    let name: String = parse_value(arg, args.next());
+
+                    output_profile = Some(
+                        find_profile(&name)
+                            .unwrap_or_else(|| panic!("Unknown output profile {name}.")),
+                    );
+                }
+                "--max-dispute-cycles" => {
+                    policy.max_dispute_cycles = Some(parse_value(arg, args.next()))
+                }
+                "--max-disputes-per-account" => {
+                    policy.max_disputes_per_account = Some(parse_value(arg, args.next()))
+                }
+                "--fee" => {
+                    let (r#type, fee) = parse_fee(&parse_value::<String>(arg, args.next()));
+                    policy.fees.insert(r#type, fee);
+                }
+                "--dispute-tolerance" => {
+                    policy.dispute_tolerance = Some(parse_value(arg, args.next()))
+                }
+                "--max-total-held" => policy.max_total_held = Some(parse_value(arg, args.next())),
+                "--format" => {
+                    let format: String = parse_value(arg, args.next());
+
+                    output_format = match format.as_str() {
+                        "csv" => OutputFormat::Csv,
+                        #[cfg(feature = "parquet")]
+                        "parquet" => OutputFormat::Parquet(parse_value(arg, args.next())),
+                        _ => panic!("Unsupported output format {format}."),
+                    };
+                }
+                "--type-codes" => {
+                    let value: String = parse_value(arg, args.next());
+
+                    type_codes = parse_type_codes(&value);
+                }
+                "--anomaly-factor" => policy.anomaly_factor = Some(parse_value(arg, args.next())),
+                "--quarantine" => quarantine_path = Some(parse_value(arg, args.next())),
+                "--withdraw-from-held" => policy.withdraw_from_held = true,
+                "--evict-settled" => policy.evict_settled = true,
+                "--input-version" => input_version = parse_value(arg, args.next()),
+                "--round-output" => round_output = Some(parse_value(arg, args.next())),
+                "--rounding-report" => rounding_report = true,
+                "--mmap" => mmap = true,
+                "--balance-series" => {
+                    let client_id = parse_value(arg, args.next());
+                    balance_series = Some((client_id, parse_value(arg, args.next())));
+                }
+                "--tolerate-broken-pipe" => tolerate_broken_pipe = true,
+                "--verify-conservation" => verify_conservation = true,
+                "--bundle" => bundle_dir = Some(parse_value(arg, args.next())),
+                "--require-activity" => require_activity = true,
+                "--input-format" => input_format = parse_value(arg, args.next()),
+                "--profile-latency" => profile_latency = true,
+                "--as-of" => as_of = Some(parse_value(arg, args.next())),
+                "--config" => {
+                    args.next();
+                }
+                "--import-state" => import_state_path = Some(parse_value(arg, args.next())),
+                "--import-refs" => import_refs_path = Some(parse_value(arg, args.next())),
+                "--result-hash" => result_hash = true,
+                "--save-state" => save_state_path = Some(parse_value(arg, args.next())),
+                "--save-state-every" => save_state_every = Some(parse_value(arg, args.next())),
+                "--resume" => resume_path = Some(parse_value(arg, args.next())),
+                _ if arg.starts_with("--") => panic!("Unknown option {arg}."),
+                _ if transactions_path.is_some() => {
+                    panic!("Unexpected argument {arg}, only one transactions file can be given.")
+                }
+                _ => transactions_path = Some(arg.clone()),
+            }
+        }
+
+        if verify_conservation && policy.evict_settled {
+            panic!("--verify-conservation can't be combined with --evict-settled.")
+        }
+
+        // Imported balances were settled by transactions which this run can't see
+        if verify_conservation && import_state_path.is_some() {
+            panic!("--verify-conservation can't be combined with --import-state.")
+        }
+
+        if rounding_report && round_output.is_none() {
+            panic!("--rounding-report requires --round-output.")
+        }
+
+        if save_state_every.is_some() && save_state_path.is_none() {
+            panic!("--save-state-every requires --save-state.")
+        }
+
+        let transactions_path = transactions_path
+            .or(file_transactions_path)
+            .unwrap_or_else(|| {
+                panic!("No transactions file provided, please specify a transaction file.")
+            });
+
+        Config {
+            transactions_path,
+            flush_every,
+            empty_client,
+            dump_refs_path,
+            max_input_scale,
+            detect_duplicate_file,
+            require_disputes_resolved,
+            output_profile,
+            policy,
+            output_format,
+            type_codes,
+            quarantine_path,
+            input_version,
+            round_output,
+            rounding_report,
+            mmap,
+            balance_series,
+            tolerate_broken_pipe,
+            verify_conservation,
+            bundle_dir,
+            require_activity,
+            input_format,
+            profile_latency,
+            as_of,
+            import_state_path,
+            import_refs_path,
+            result_hash,
+            save_state_path,
+            save_state_every,
+            resume_path,
+            args: given_args,
+        }
+    }
+}
+
+/// Turns a TOML config file into its input path, from the `transactions` key, and the equivalent
+/// flags. Other keys are flag names without the leading `--`. `true` sets a switch, arrays repeat
+/// a flag once per element and nested arrays pass several values to one flag, e.g.
+/// `format = [["parquet", "out.parquet"]]`.
+fn config_file_args(path: &str) -> (Option<String>, Vec<String>) {
+    let table: toml::Table = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Couldn't read config file {path}: {e}."))
+        .parse()
+        .unwrap_or_else(|e| panic!("Invalid config file {path}: {e}"));
+
+    let mut transactions_path = None;
+    let mut args = Vec::new();
+
+    for (key, value) in table {
+        if key == "transactions" {
+            transactions_path = Some(config_value(&key, value));
+            continue;
         }
 
-        let transactions_path = args[1].clone();
+        let flag = format!("--{key}");
+
+        match value {
+            Value::Boolean(true) => args.push(flag),
+            Value::Boolean(false) => {}
+            Value::Array(values) => {
+                for value in values {
+                    args.push(flag.clone());
+
+                    match value {
+                        Value::Array(values) => {
+                            args.extend(values.into_iter().map(|value| config_value(&key, value)))
+                        }
+                        value => args.push(config_value(&key, value)),
+                    }
+                }
+            }
+            value => args.extend([flag, config_value(&key, value)]),
+        }
+    }
+
+    (transactions_path, args)
+}
 
-        Config { transactions_path }
+fn config_value(key: &str, value: Value) -> String {
+    match value {
+        Value::String(value) => value,
+        Value::Integer(value) => value.to_string(),
+        Value::Float(value) => value.to_string(),
+        _ => panic!("Unsupported value for {key} in config file."),
     }
 }
+
+/// Parses a type code mapping such as `1=deposit,2=withdrawal`
+fn parse_type_codes(value: &str) -> TypeCodes {
+    value
+        .split(',')
+        .map(|pair| {
+            pair.split_once('=')
+                .and_then(|(code, name)| {
+                    Some((code.trim().parse().ok()?, name.trim().parse().ok()?))
+                })
+                .unwrap_or_else(|| panic!("Invalid type code mapping {pair}."))
+        })
+        .collect()
+}
+
+fn parse_fee(value: &str) -> (TransactionType, Decimal) {
+    let fee = value
+        .split_once('=')
+        .and_then(|(name, amount)| Some((name.trim().parse().ok()?, amount.trim().parse().ok()?)));
+
+    match fee {
+        Some(fee @ (TransactionType::Deposit | TransactionType::Withdrawal, _)) => fee,
+        Some(_) => panic!("Fees can only be charged on deposits and withdrawals."),
+        None => panic!("Invalid fee {value}."),
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<&String>) -> T {
+    value
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| panic!("{flag} requires a valid value."))
+}
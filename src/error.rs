@@ -0,0 +1,185 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use rust_decimal::Decimal;
+
+use crate::transaction::{TransactionState, TransactionType};
+
+#[derive(Debug, PartialEq)]
+pub enum PaymentError {
+    /// The input file couldn't be opened or read
+    Io { path: String, message: String },
+    /// The input's header lacks a column required by its schema version
+    MissingColumn { column: &'static str },
+    /// A row had an empty `client` field
+    EmptyClient { tx_id: u32 },
+    /// A row used an integer type code which isn't configured
+    UnknownTypeCode { tx_id: u32, code: u32 },
+    /// A row's amount has more decimal places than the configured maximum
+    ExcessiveScale {
+        tx_id: u32,
+        scale: u32,
+        max_scale: u32,
+    },
+    /// A withdrawal exceeded the funds it was allowed to draw from
+    InsufficientFunds { tx_id: u32 },
+    /// A deposit was smaller than the fee charged on it
+    DepositBelowFee { tx_id: u32 },
+    /// A deposit, withdrawal or authorization reused the tx id of an earlier one
+    DuplicateTransaction { tx_id: u32 },
+    /// A deposit, withdrawal or authorization was settled by more than one merged ledger
+    MergedDuplicate { tx_id: u32 },
+    /// An open authorization was imported without the pending funds to capture it
+    UncoveredAuthorization { tx_id: u32 },
+    /// A contiguous block of rows repeats earlier rows, as if the input was concatenated with itself
+    DuplicateBlock { first_tx_id: u32, rows: usize },
+    /// A deposit or withdrawal was far larger than the account's average transaction
+    Anomaly {
+        tx_id: u32,
+        amount: Decimal,
+        average: Decimal,
+    },
+    /// A dispute would hold more than the referenced transaction's undisputed amount
+    ExcessiveDispute { tx_id: u32 },
+    /// A resolve would release more than is held by the referenced transaction's disputes
+    ExcessiveResolve { tx_id: u32 },
+    /// Disputes were still open once processing finished
+    OpenDisputes { tx_ids: Vec<u32> },
+    /// The referenced transaction has already been disputed and resolved the maximum number of times
+    DisputeCycleLimit { tx_id: u32, max_cycles: u32 },
+    /// The account has already opened the maximum number of disputes
+    AccountDisputeLimit {
+        tx_id: u32,
+        client_id: u16,
+        max_disputes: u32,
+    },
+    /// A dispute would take the funds held across all accounts over the configured maximum
+    HeldLimit { tx_id: u32, max_total_held: Decimal },
+    /// A client's total doesn't match its deposits less its withdrawals and chargebacks
+    ConservationViolation {
+        client_id: u16,
+        expected: Decimal,
+        total: Decimal,
+    },
+    /// The referenced transaction hasn't been processed (yet)
+    UnknownReference { tx_id: u32 },
+    /// The referenced transaction belongs to a different client
+    ForeignReference { tx_id: u32, client_id: u16 },
+    /// The referenced transaction's lifecycle doesn't allow the attempted transaction
+    InvalidTransition {
+        tx_id: u32,
+        state: TransactionState,
+        attempted: TransactionType,
+    },
+}
+
+impl Display for PaymentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentError::Io { path, message } => write!(f, "failed to read {path}: {message}"),
+            PaymentError::MissingColumn { column } => {
+                write!(f, "input is missing the `{column}` column")
+            }
+            PaymentError::EmptyClient { tx_id } => {
+                write!(f, "transaction {tx_id} has an empty client")
+            }
+            PaymentError::UnknownTypeCode { tx_id, code } => {
+                write!(f, "transaction {tx_id} has unknown type code {code}")
+            }
+            PaymentError::ExcessiveScale {
+                tx_id,
+                scale,
+                max_scale,
+            } => write!(
+                f,
+                "transaction {tx_id} has an amount with {scale} decimal places, more than the maximum of {max_scale}"
+            ),
+            PaymentError::InsufficientFunds { tx_id } => {
+                write!(f, "transaction {tx_id} withdraws more than the account's funds")
+            }
+            PaymentError::DepositBelowFee { tx_id } => {
+                write!(f, "deposit {tx_id} doesn't cover its fee")
+            }
+            PaymentError::DuplicateTransaction { tx_id } => {
+                write!(f, "transaction {tx_id} reuses the id of an earlier transaction")
+            }
+            PaymentError::MergedDuplicate { tx_id } => write!(
+                f,
+                "transaction {tx_id} was settled by more than one merged ledger, every copy is in the balances"
+            ),
+            PaymentError::UncoveredAuthorization { tx_id } => write!(
+                f,
+                "authorization {tx_id} was imported without the pending funds to capture it"
+            ),
+            PaymentError::DuplicateBlock { first_tx_id, rows } => write!(
+                f,
+                "{rows} rows starting at transaction {first_tx_id} repeat earlier rows, the input may have been processed twice"
+            ),
+            PaymentError::Anomaly {
+                tx_id,
+                amount,
+                average,
+            } => write!(
+                f,
+                "transaction {tx_id} of {amount} is far larger than the account's average of {average}"
+            ),
+            PaymentError::ExcessiveDispute { tx_id } => write!(
+                f,
+                "dispute of transaction {tx_id} exceeds its undisputed amount"
+            ),
+            PaymentError::ExcessiveResolve { tx_id } => write!(
+                f,
+                "resolve of transaction {tx_id} exceeds the amount held by its disputes"
+            ),
+            PaymentError::OpenDisputes { tx_ids } => {
+                let tx_ids: Vec<String> = tx_ids.iter().map(|id| id.to_string()).collect();
+
+                write!(f, "disputes remain open for transactions {}", tx_ids.join(", "))
+            }
+            PaymentError::DisputeCycleLimit { tx_id, max_cycles } => write!(
+                f,
+                "transaction {tx_id} has already been disputed and resolved {max_cycles} times"
+            ),
+            PaymentError::AccountDisputeLimit {
+                tx_id,
+                client_id,
+                max_disputes,
+            } => write!(
+                f,
+                "dispute of transaction {tx_id} exceeds client {client_id}'s limit of {max_disputes} disputes"
+            ),
+            PaymentError::HeldLimit {
+                tx_id,
+                max_total_held,
+            } => write!(
+                f,
+                "dispute of transaction {tx_id} would hold more than {max_total_held} across all accounts"
+            ),
+            PaymentError::ConservationViolation {
+                client_id,
+                expected,
+                total,
+            } => write!(
+                f,
+                "client {client_id} has a total of {total} but its transactions add up to {expected}"
+            ),
+            PaymentError::UnknownReference { tx_id } => {
+                write!(f, "transaction {tx_id} references an unknown transaction")
+            }
+            PaymentError::ForeignReference { tx_id, client_id } => write!(
+                f,
+                "client {client_id} references transaction {tx_id} of another client"
+            ),
+            PaymentError::InvalidTransition {
+                tx_id,
+                state,
+                attempted,
+            } => write!(
+                f,
+                "cannot apply {attempted:?} to transaction {tx_id} in state {state:?}"
+            ),
+        }
+    }
+}
+
+impl Error for PaymentError {}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use crate::transaction::TransactionType;
+
+/// How long settling each transaction took, by transaction type
+#[derive(Debug, Default)]
+pub struct Latencies {
+    samples: HashMap<TransactionType, Vec<Duration>>,
+}
+
+impl Latencies {
+    pub fn record(&mut self, r#type: TransactionType, elapsed: Duration) {
+        self.samples.entry(r#type).or_default().push(elapsed);
+    }
+
+    pub fn merge(&mut self, other: Latencies) {
+        for (r#type, samples) in other.samples {
+            self.samples.entry(r#type).or_default().extend(samples);
+        }
+    }
+
+    /// Returns one line per observed transaction type with its p50, p95 and p99 latencies
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+
+        for (r#type, _) in TransactionType::ALL {
+            let Some(samples) = self.samples.get(&r#type) else {
+                continue;
+            };
+
+            let mut samples = samples.clone();
+            samples.sort_unstable();
+
+            // Nearest-rank percentile
+            let percentile = |p: usize| samples[(samples.len() * p).div_ceil(100).max(1) - 1];
+
+            let _ = writeln!(
+                report,
+                "{}: n={} p50={:?} p95={:?} p99={:?}",
+                r#type.name(),
+                samples.len(),
+                percentile(50),
+                percentile(95),
+                percentile(99)
+            );
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::{latency::Latencies, ledger::Ledger, tests::tx, transaction::TransactionType};
+
+    #[test]
+    fn report_latency_per_observed_type() {
+        let mut ledger = Ledger::new();
+        ledger.latencies = Some(Latencies::default());
+
+        for tx in [
+            tx(TransactionType::Deposit, 1, 1, Decimal::new(10, 0)),
+            tx(TransactionType::Deposit, 1, 2, Decimal::new(10, 0)),
+            tx(TransactionType::Withdrawal, 1, 3, Decimal::new(10, 0)),
+            tx(TransactionType::Dispute, 1, 1, None),
+        ] {
+            assert_eq!(ledger.settle(&tx), Ok(()));
+        }
+
+        let report = ledger
+            .latencies
+            .expect("latencies should be recorded")
+            .report();
+        let types: Vec<_> = report
+            .lines()
+            .map(|line| line.split(':').next().unwrap_or_default())
+            .collect();
+
+        assert_eq!(types, ["deposit", "withdrawal", "dispute"]);
+        assert!(report.starts_with("deposit: n=2 p50="));
+    }
+}
@@ -0,0 +1,1288 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Instant;
+
+use rust_decimal::Decimal;
+
+use crate::account::Account;
+use crate::amount::Amount;
+use crate::error::PaymentError;
+use crate::latency::Latencies;
+use crate::state::{EngineState, SavedRecord};
+use crate::transaction::{Transaction, TransactionRecord, TransactionState, TransactionType};
+
+pub type AccountsDB<A = Decimal> = HashMap<u16, Account<A>>;
+pub type TransactionsDB<A = Decimal> = HashMap<u32, TransactionRecord<A>>;
+
+/// Optional limits applied while settling transactions
+#[derive(Debug, Clone, Default)]
+pub struct SettlementPolicy {
+    /// How many times a single transaction may go through a dispute and resolve
+    pub max_dispute_cycles: Option<u32>,
+    /// Deposits and withdrawals larger than this multiple of the account's average are reported
+    pub anomaly_factor: Option<Decimal>,
+    /// Whether withdrawals may draw from held funds once available funds run out
+    pub withdraw_from_held: bool,
+    /// Whether resolved, charged back and expired transactions are dropped from the retained
+    /// transactions, so they can't be disputed or reversed again
+    pub evict_settled: bool,
+    /// How many disputes a single account may open
+    pub max_disputes_per_account: Option<u32>,
+    /// Fee charged on each deposit or withdrawal, by type
+    pub fees: HashMap<TransactionType, Decimal>,
+    /// How far a dispute may exceed the referenced transaction's undisputed amount
+    pub dispute_tolerance: Option<Decimal>,
+    /// Most funds which may be held across all accounts at once
+    pub max_total_held: Option<Decimal>,
+}
+
+/// Holds account balances along with the transactions retained for dispute handling
+pub struct Ledger<A = Decimal> {
+    pub accounts: AccountsDB<A>,
+    pub ref_txs: TransactionsDB<A>,
+    pub errors: Vec<PaymentError>,
+    /// Noteworthy transactions which were still applied
+    pub warnings: Vec<PaymentError>,
+    pub policy: SettlementPolicy,
+    /// Fees charged so far, by transaction type
+    pub fee_totals: HashMap<TransactionType, Decimal>,
+    /// Settlement times, only recorded when set
+    pub latencies: Option<Latencies>,
+    /// Running sum and count of each account's deposit and withdrawal amounts
+    transaction_sizes: HashMap<u16, (Decimal, u32)>,
+    /// Ids of evicted transactions, kept so their ids can't be reused
+    evicted: HashSet<u32>,
+    /// How many disputes each account has opened
+    disputes_opened: HashMap<u16, u32>,
+    /// Held funds summed across all accounts
+    total_held: A,
+}
+
+impl<A: Amount> Ledger<A> {
+    pub fn new() -> Ledger<A> {
+        Ledger::with_policy(SettlementPolicy::default())
+    }
+
+    pub fn with_policy(policy: SettlementPolicy) -> Ledger<A> {
+        Ledger {
+            accounts: AccountsDB::new(),
+            ref_txs: TransactionsDB::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            policy,
+            fee_totals: HashMap::new(),
+            latencies: None,
+            transaction_sizes: HashMap::new(),
+            evicted: HashSet::new(),
+            disputes_opened: HashMap::new(),
+            total_held: A::zero(),
+        }
+    }
+
+    /// Settles a single transaction against its client's account. Transactions which reference
+    /// another transaction are checked against that transaction's lifecycle before being applied.
+    pub fn settle(&mut self, tx: &Transaction<A>) -> Result<(), PaymentError> {
+        let held_before = self.held(tx.client_id);
+        let start = self.latencies.is_some().then(Instant::now);
+
+        let result = self.apply(tx);
+
+        if let (Some(latencies), Some(start)) = (&mut self.latencies, start) {
+            latencies.record(tx.r#type, start.elapsed());
+        }
+
+        self.total_held += self.held(tx.client_id) - held_before;
+
+        result
+    }
+
+    fn held(&self, client_id: u16) -> A {
+        self.accounts
+            .get(&client_id)
+            .map_or_else(A::zero, |acc| acc.funds_held)
+    }
+
+    fn apply(&mut self, tx: &Transaction<A>) -> Result<(), PaymentError> {
+        let acc = self
+            .accounts
+            .entry(tx.client_id)
+            .or_insert_with(|| Account::new(tx.client_id));
+
+        if tx.r#type.is_referenceable() {
+            if self.ref_txs.contains_key(&tx.tx_id) || self.evicted.contains(&tx.tx_id) {
+                return Err(PaymentError::DuplicateTransaction { tx_id: tx.tx_id });
+            }
+
+            let fee = match (self.policy.fees.get(&tx.r#type), tx.amount) {
+                (Some(fee), Some(_)) => Some(*fee),
+                _ => None,
+            };
+
+            match tx.r#type {
+                TransactionType::Withdrawal => {
+                    // The fee has to be covered along with the withdrawal itself
+                    let charged_tx = Transaction {
+                        amount: tx
+                            .amount
+                            .map(|amount| amount + A::from_decimal(fee.unwrap_or_default())),
+                        ..*tx
+                    };
+
+                    let held_before = acc.funds_held;
+
+                    acc.settle_withdrawal(&charged_tx, self.policy.withdraw_from_held)?;
+
+                    let drawn = held_before - acc.funds_held;
+
+                    if drawn > A::zero() {
+                        self.release_disputed(tx.client_id, drawn);
+                    }
+                }
+                _ => {
+                    // A fee larger than the deposit would take the account below zero
+                    if let (Some(fee), Some(amount)) = (fee, tx.amount) {
+                        if amount < A::from_decimal(fee) {
+                            return Err(PaymentError::DepositBelowFee { tx_id: tx.tx_id });
+                        }
+                    }
+
+                    acc.settle_transaction(tx, None)?;
+
+                    if let Some(fee) = fee {
+                        acc.funds_available -= A::from_decimal(fee);
+                        acc.funds_total -= A::from_decimal(fee);
+                    }
+                }
+            }
+
+            if let Some(fee) = fee {
+                *self.fee_totals.entry(tx.r#type).or_default() += fee;
+            }
+
+            self.ref_txs.insert(tx.tx_id, TransactionRecord::new(*tx));
+
+            if matches!(
+                tx.r#type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            ) {
+                self.track_transaction_size(tx);
+            }
+
+            return Ok(());
+        }
+
+        let record = self
+            .ref_txs
+            .get_mut(&tx.tx_id)
+            .ok_or(PaymentError::UnknownReference { tx_id: tx.tx_id })?;
+
+        if record.tx.client_id != tx.client_id {
+            return Err(PaymentError::ForeignReference {
+                tx_id: tx.tx_id,
+                client_id: tx.client_id,
+            });
+        }
+
+        let mut next_state =
+            record
+                .state
+                .transition(tx.r#type)
+                .ok_or(PaymentError::InvalidTransition {
+                    tx_id: tx.tx_id,
+                    state: record.state,
+                    attempted: tx.r#type,
+                })?;
+
+        if let Some(max_cycles) = self.policy.max_dispute_cycles {
+            // Further partial disputes of an open dispute belong to the same cycle
+            if tx.r#type == TransactionType::Dispute
+                && record.state != TransactionState::Disputed
+                && record.dispute_cycles >= max_cycles
+            {
+                return Err(PaymentError::DisputeCycleLimit {
+                    tx_id: tx.tx_id,
+                    max_cycles,
+                });
+            }
+        }
+
+        let disputes_opened = self.disputes_opened.entry(tx.client_id).or_default();
+
+        if let Some(max_disputes) = self.policy.max_disputes_per_account {
+            if tx.r#type == TransactionType::Dispute && *disputes_opened >= max_disputes {
+                return Err(PaymentError::AccountDisputeLimit {
+                    tx_id: tx.tx_id,
+                    client_id: tx.client_id,
+                    max_disputes,
+                });
+            }
+        }
+
+        let ref_amount = record.tx.amount.unwrap_or_else(A::zero);
+
+        // Disputes and resolves may carry an amount to only cover part of the referenced
+        // transaction, otherwise they cover whatever remains
+        let amount = match tx.r#type {
+            TransactionType::Dispute => {
+                let undisputed = ref_amount - record.disputed;
+                let mut amount = tx.amount.unwrap_or(undisputed);
+
+                // Disputes slightly over the referenced amount, e.g. after upstream fee
+                // adjustments, only hold what's left undisputed
+                if let Some(tolerance) = self.policy.dispute_tolerance {
+                    if amount > undisputed && amount <= undisputed + A::from_decimal(tolerance) {
+                        amount = undisputed;
+                    }
+                }
+
+                if amount <= A::zero() || amount > undisputed {
+                    return Err(PaymentError::ExcessiveDispute { tx_id: tx.tx_id });
+                }
+
+                if let Some(max_total_held) = self.policy.max_total_held {
+                    if self.total_held + amount > A::from_decimal(max_total_held) {
+                        return Err(PaymentError::HeldLimit {
+                            tx_id: tx.tx_id,
+                            max_total_held,
+                        });
+                    }
+                }
+
+                amount
+            }
+            TransactionType::Resolve => {
+                let amount = tx.amount.unwrap_or(record.disputed);
+
+                // A dispute whose held funds were all withdrawn can be closed without an amount
+                if amount < A::zero()
+                    || amount > record.disputed
+                    || (amount == A::zero() && tx.amount.is_some())
+                {
+                    return Err(PaymentError::ExcessiveResolve { tx_id: tx.tx_id });
+                }
+
+                amount
+            }
+            TransactionType::Chargeback => record.disputed,
+            TransactionType::Reversal => record.charged_back,
+            _ => ref_amount,
+        };
+
+        let effective_tx = Transaction {
+            amount: Some(amount),
+            ..record.tx
+        };
+
+        acc.settle_transaction(tx, Some(&effective_tx))?;
+
+        match tx.r#type {
+            TransactionType::Dispute => {
+                record.disputed += amount;
+                *disputes_opened += 1;
+            }
+            TransactionType::Resolve => {
+                record.disputed -= amount;
+
+                if record.disputed > A::zero() {
+                    next_state = TransactionState::Disputed;
+                } else {
+                    record.dispute_cycles += 1;
+                }
+            }
+            TransactionType::Chargeback => {
+                record.charged_back = amount;
+                record.disputed = A::zero();
+            }
+            _ => {}
+        }
+
+        record.state = next_state;
+
+        if self.policy.evict_settled
+            && matches!(
+                next_state,
+                TransactionState::Resolved
+                    | TransactionState::ChargedBack
+                    | TransactionState::Reversed
+                    | TransactionState::Expired
+            )
+        {
+            self.ref_txs.remove(&tx.tx_id);
+            self.evicted.insert(tx.tx_id);
+        }
+
+        Ok(())
+    }
+
+    /// Takes `amount` withdrawn from a client's held funds out of its open disputes, lowest tx id
+    /// first, so later resolves and chargebacks only move what's still held
+    fn release_disputed(&mut self, client_id: u16, mut amount: A) {
+        let mut records: Vec<_> = self
+            .ref_txs
+            .values_mut()
+            .filter(|record| {
+                record.tx.client_id == client_id && record.state == TransactionState::Disputed
+            })
+            .collect();
+        records.sort_unstable_by_key(|record| record.tx.tx_id);
+
+        for record in records {
+            let released = if record.disputed < amount {
+                record.disputed
+            } else {
+                amount
+            };
+
+            record.disputed -= released;
+            amount -= released;
+
+            if amount <= A::zero() {
+                break;
+            }
+        }
+    }
+
+    /// Flags `tx` if it exceeds the anomaly factor times the account's prior average transaction
+    /// size, then folds it into that average
+    fn track_transaction_size(&mut self, tx: &Transaction<A>) {
+        let amount = match tx.amount {
+            Some(amount) => amount.to_decimal(),
+            None => return,
+        };
+
+        let (sum, count) = self
+            .transaction_sizes
+            .entry(tx.client_id)
+            .or_insert((Decimal::new(0, 0), 0));
+
+        if let Some(factor) = self.policy.anomaly_factor {
+            if *count > 0 {
+                let average = *sum / Decimal::from(*count);
+
+                if amount > average * factor {
+                    self.warnings.push(PaymentError::Anomaly {
+                        tx_id: tx.tx_id,
+                        amount,
+                        average,
+                    });
+                }
+            }
+        }
+
+        *sum += amount;
+        *count += 1;
+    }
+
+    /// Iterates over the accounts in ascending client id order, regardless of `HashMap` ordering
+    pub fn iter_sorted(&self) -> impl Iterator<Item = (&u16, &Account<A>)> {
+        let mut accounts: Vec<_> = self.accounts.iter().collect();
+        accounts.sort_unstable_by_key(|(id, _)| **id);
+
+        accounts.into_iter()
+    }
+
+    /// Adds accounts carried over from an earlier run
+    pub fn import_accounts(&mut self, accounts: AccountsDB<A>) {
+        for (client_id, account) in accounts {
+            self.total_held += account.funds_held;
+
+            if let Some(replaced) = self.accounts.insert(client_id, account) {
+                self.total_held -= replaced.funds_held;
+            }
+        }
+    }
+
+    /// Retains deposits, withdrawals and authorizations from an earlier run, e.g. a `--dump-refs`
+    /// file, in whatever state that run left them so new disputes, resolves and chargebacks can
+    /// reference them. Balances are left alone since imported accounts already include these
+    /// transactions, but charged back records count towards their account's lock again. Open
+    /// authorizations which the imported accounts' pending funds don't cover are rejected, so
+    /// capturing them can't take pending below zero.
+    pub fn import_refs(&mut self, records: impl IntoIterator<Item = TransactionRecord<A>>) {
+        let mut uncaptured: HashMap<u16, A> = HashMap::new();
+
+        for record in records {
+            if !record.tx.r#type.is_referenceable() || self.ref_txs.contains_key(&record.tx.tx_id) {
+                continue;
+            }
+
+            if record.state == TransactionState::Authorized {
+                let pending = self
+                    .accounts
+                    .get(&record.tx.client_id)
+                    .map_or(A::zero(), |acc| acc.funds_pending);
+                let claimed = uncaptured.entry(record.tx.client_id).or_insert(A::zero());
+                let amount = record.tx.amount.unwrap_or(A::zero());
+
+                if *claimed + amount > pending {
+                    self.errors.push(PaymentError::UncoveredAuthorization {
+                        tx_id: record.tx.tx_id,
+                    });
+                    continue;
+                }
+
+                *claimed += amount;
+            }
+
+            if record.state == TransactionState::ChargedBack {
+                if let Some(acc) = self.accounts.get_mut(&record.tx.client_id) {
+                    acc.chargebacks.insert(record.tx.tx_id);
+                }
+            }
+
+            self.ref_txs.insert(record.tx.tx_id, record);
+        }
+    }
+
+    /// Combines another ledger settled from separate input into this one. Accounts present in both
+    /// have their balances added. Reference transactions whose ids were already retained here keep
+    /// the retained record and are reported as warnings, since each ledger has already applied its
+    /// copy to the balances.
+    pub fn merge(&mut self, other: Ledger<A>) {
+        self.total_held += other.total_held;
+
+        for (client_id, account) in other.accounts {
+            match self.accounts.get_mut(&client_id) {
+                Some(acc) => acc.merge(account),
+                None => {
+                    self.accounts.insert(client_id, account);
+                }
+            }
+        }
+
+        for (tx_id, record) in other.ref_txs {
+            if self.ref_txs.contains_key(&tx_id) || self.evicted.contains(&tx_id) {
+                self.warnings.push(PaymentError::MergedDuplicate { tx_id });
+            } else {
+                self.ref_txs.insert(tx_id, record);
+            }
+        }
+
+        for (client_id, (sum, count)) in other.transaction_sizes {
+            let sizes = self
+                .transaction_sizes
+                .entry(client_id)
+                .or_insert((Decimal::new(0, 0), 0));
+
+            sizes.0 += sum;
+            sizes.1 += count;
+        }
+
+        for (client_id, count) in other.disputes_opened {
+            *self.disputes_opened.entry(client_id).or_default() += count;
+        }
+
+        for (r#type, total) in other.fee_totals {
+            *self.fee_totals.entry(r#type).or_default() += total;
+        }
+
+        match (&mut self.latencies, other.latencies) {
+            (Some(latencies), Some(other)) => latencies.merge(other),
+            (None, other) => self.latencies = other,
+            _ => {}
+        }
+
+        self.evicted.extend(other.evicted);
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+    }
+
+    /// Checks every account's total against its deposits and captures, less its successful
+    /// withdrawals, fees and outstanding chargebacks, reporting the clients where they differ in
+    /// client order. Relies on every settled transaction being retained, so it can't detect
+    /// anything once settled transactions are evicted.
+    pub fn verify_conservation(&self) -> Vec<PaymentError> {
+        let mut expected = HashMap::<u16, Decimal>::new();
+
+        for record in self.ref_txs.values() {
+            let amount = record.tx.amount.map_or(Decimal::ZERO, A::to_decimal);
+
+            let flow = match (record.tx.r#type, record.state) {
+                (TransactionType::Withdrawal, _) => -amount,
+                (
+                    TransactionType::Authorize,
+                    TransactionState::Authorized | TransactionState::Expired,
+                ) => Decimal::ZERO,
+                _ => amount,
+            };
+
+            let fee = match record.tx.amount {
+                Some(_) => self.policy.fees.get(&record.tx.r#type).copied(),
+                None => None,
+            };
+
+            let charged_back = match record.state {
+                TransactionState::ChargedBack => record.charged_back.to_decimal(),
+                _ => Decimal::ZERO,
+            };
+
+            *expected.entry(record.tx.client_id).or_default() +=
+                flow - charged_back - fee.unwrap_or_default();
+        }
+
+        self.iter_sorted()
+            .filter_map(|(client_id, acc)| {
+                let expected = expected.get(client_id).copied().unwrap_or_default();
+                let total = acc.funds_total.to_decimal();
+
+                (expected != total).then_some(PaymentError::ConservationViolation {
+                    client_id: *client_id,
+                    expected,
+                    total,
+                })
+            })
+            .collect()
+    }
+
+    /// Captures everything needed to resume settling later, along with the number of input
+    /// transactions taken off the queue and the transactions requeued since. Errors, warnings and
+    /// latencies aren't included.
+    pub fn save_state(
+        &self,
+        position: usize,
+        requeued: &VecDeque<Transaction<A>>,
+    ) -> EngineState<A> {
+        let mut ref_txs: Vec<SavedRecord<A>> = self.ref_txs.values().map(Into::into).collect();
+        ref_txs.sort_unstable_by_key(|record| record.tx.tx_id);
+
+        let mut evicted: Vec<u32> = self.evicted.iter().copied().collect();
+        evicted.sort_unstable();
+
+        let mut disputes_opened: Vec<(u16, u32)> = self
+            .disputes_opened
+            .iter()
+            .map(|(id, n)| (*id, *n))
+            .collect();
+        disputes_opened.sort_unstable();
+
+        let mut transaction_sizes: Vec<(u16, Decimal, u32)> = self
+            .transaction_sizes
+            .iter()
+            .map(|(id, (sum, count))| (*id, *sum, *count))
+            .collect();
+        transaction_sizes.sort_unstable_by_key(|(id, _, _)| *id);
+
+        let mut fee_totals: Vec<(TransactionType, Decimal)> = self
+            .fee_totals
+            .iter()
+            .map(|(t, total)| (*t, *total))
+            .collect();
+        fee_totals.sort_unstable_by_key(|(r#type, _)| r#type.name());
+
+        EngineState {
+            accounts: self.iter_sorted().map(|(_, acc)| acc.into()).collect(),
+            ref_txs,
+            evicted,
+            disputes_opened,
+            transaction_sizes,
+            fee_totals,
+            position,
+            requeued: requeued.iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Rebuilds a ledger saved by `save_state`, returning it with the number of input transactions
+    /// to skip and the requeued transactions to retry after the rest of the input. The policy isn't
+    /// saved, so it's given again here.
+    pub fn resume(
+        state: EngineState<A>,
+        policy: SettlementPolicy,
+    ) -> (Ledger<A>, usize, VecDeque<Transaction<A>>) {
+        let mut ledger = Ledger::with_policy(policy);
+
+        ledger.import_accounts(
+            state
+                .accounts
+                .into_iter()
+                .map(|saved| (saved.client_id, saved.into()))
+                .collect(),
+        );
+
+        ledger.ref_txs = state
+            .ref_txs
+            .into_iter()
+            .map(|saved| (saved.tx.tx_id, saved.into()))
+            .collect();
+        ledger.evicted = state.evicted.into_iter().collect();
+        ledger.disputes_opened = state.disputes_opened.into_iter().collect();
+        ledger.transaction_sizes = state
+            .transaction_sizes
+            .into_iter()
+            .map(|(id, sum, count)| (id, (sum, count)))
+            .collect();
+        ledger.fee_totals = state.fee_totals.into_iter().collect();
+
+        let requeued = state.requeued.into_iter().map(Into::into).collect();
+
+        (ledger, state.position, requeued)
+    }
+
+    /// Returns the tx ids of transactions which are still under dispute, in ascending order
+    pub fn open_disputes(&self) -> Vec<u32> {
+        let mut tx_ids: Vec<u32> = self
+            .ref_txs
+            .values()
+            .filter(|record| record.state == TransactionState::Disputed)
+            .map(|record| record.tx.tx_id)
+            .collect();
+
+        tx_ids.sort_unstable();
+        tx_ids
+    }
+}
+
+impl<A: Amount> Default for Ledger<A> {
+    fn default() -> Self {
+        Ledger::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rust_decimal::Decimal;
+
+    use crate::{
+        error::PaymentError,
+        ledger::{Ledger, SettlementPolicy},
+        tests::tx,
+        transaction::{Transaction, TransactionState, TransactionType},
+    };
+
+    #[test]
+    fn reject_resolve_after_chargeback() {
+        let deposit_tx = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::new(500, 0)),
+            timestamp: None,
+        };
+
+        let dispute_tx = Transaction {
+            r#type: TransactionType::Dispute,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            timestamp: None,
+        };
+
+        let chargeback_tx = Transaction {
+            r#type: TransactionType::Chargeback,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            timestamp: None,
+        };
+
+        let resolve_tx = Transaction {
+            r#type: TransactionType::Resolve,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            timestamp: None,
+        };
+
+        let mut ledger = Ledger::new();
+
+        assert_eq!(ledger.settle(&deposit_tx), Ok(()));
+        assert_eq!(ledger.settle(&dispute_tx), Ok(()));
+        assert_eq!(ledger.settle(&chargeback_tx), Ok(()));
+
+        assert_eq!(
+            ledger.settle(&resolve_tx),
+            Err(PaymentError::InvalidTransition {
+                tx_id: 1,
+                state: TransactionState::ChargedBack,
+                attempted: TransactionType::Resolve,
+            })
+        );
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available, Decimal::new(0, 0));
+        assert_eq!(acc.funds_held, Decimal::new(0, 0));
+        assert_eq!(acc.funds_total, Decimal::new(0, 0));
+        assert!(acc.locked);
+    }
+
+    #[test]
+    fn reject_references_to_other_clients() {
+        let mut ledger = Ledger::new();
+
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Deposit, 1, 1, Decimal::new(10, 0))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Deposit, 2, 2, Decimal::new(5, 0))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 2, 1, None)),
+            Err(PaymentError::ForeignReference {
+                tx_id: 1,
+                client_id: 2,
+            })
+        );
+
+        for (client_id, available) in [(1, 10), (2, 5)] {
+            let acc = ledger
+                .accounts
+                .get(&client_id)
+                .expect("Client should exist in ledger");
+
+            assert_eq!(acc.funds_available, Decimal::new(available, 0));
+            assert_eq!(acc.funds_held, Decimal::new(0, 0));
+        }
+
+        assert_eq!(ledger.ref_txs[&1].state, TransactionState::Processed);
+    }
+
+    #[test]
+    fn reverse_partial_chargeback() {
+        let mut ledger = Ledger::new();
+
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Deposit, 1, 1, Decimal::new(10, 0))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 1, 1, Decimal::new(4, 0))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Chargeback, 1, 1, None)),
+            Ok(())
+        );
+
+        let record = &ledger.ref_txs[&1];
+
+        assert_eq!(record.state, TransactionState::ChargedBack);
+        assert_eq!(record.charged_back, Decimal::new(4, 0));
+        assert_eq!(ledger.accounts[&1].funds_total, Decimal::new(6, 0));
+        assert!(ledger.accounts[&1].locked);
+
+        // Only the partially disputed amount which was charged back is restored
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Reversal, 1, 1, None)),
+            Ok(())
+        );
+
+        assert_eq!(ledger.ref_txs[&1].state, TransactionState::Reversed);
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available, Decimal::new(10, 0));
+        assert_eq!(acc.funds_held, Decimal::new(0, 0));
+        assert_eq!(acc.funds_total, Decimal::new(10, 0));
+        assert!(!acc.locked);
+
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Reversal, 1, 1, None)),
+            Err(PaymentError::InvalidTransition {
+                tx_id: 1,
+                state: TransactionState::Reversed,
+                attempted: TransactionType::Reversal,
+            })
+        );
+    }
+
+    #[test]
+    fn expire_uncaptured_authorization() {
+        let mut ledger = Ledger::new();
+
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Authorize, 1, 1, Decimal::new(25, 0))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Expire, 1, 1, None)),
+            Ok(())
+        );
+
+        assert_eq!(ledger.ref_txs[&1].state, TransactionState::Expired);
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Capture, 1, 1, None)),
+            Err(PaymentError::InvalidTransition {
+                tx_id: 1,
+                state: TransactionState::Expired,
+                attempted: TransactionType::Capture,
+            })
+        );
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available, Decimal::new(0, 0));
+        assert_eq!(acc.funds_pending, Decimal::new(0, 0));
+        assert_eq!(acc.funds_total, Decimal::new(0, 0));
+    }
+
+    #[test]
+    fn settle_dispute_after_withdrawing_held_funds() {
+        for (closing_type, total) in [
+            (TransactionType::Resolve, 5),
+            (TransactionType::Chargeback, 0),
+        ] {
+            let mut ledger = Ledger::with_policy(SettlementPolicy {
+                withdraw_from_held: true,
+                ..Default::default()
+            });
+
+            for tx in [
+                tx(TransactionType::Deposit, 1, 1, Decimal::new(10, 0)),
+                tx(TransactionType::Dispute, 1, 1, None),
+                tx(TransactionType::Withdrawal, 1, 2, Decimal::new(5, 0)),
+            ] {
+                assert_eq!(ledger.settle(&tx), Ok(()));
+            }
+
+            // Only what's left of the dispute after the withdrawal is still held
+            assert_eq!(ledger.ref_txs[&1].disputed, Decimal::new(5, 0));
+            assert_eq!(ledger.settle(&tx(closing_type, 1, 1, None)), Ok(()));
+
+            let acc = ledger
+                .accounts
+                .get(&1)
+                .expect("Client 1 should exist in ledger");
+
+            assert_eq!(acc.funds_available, Decimal::new(total, 0));
+            assert_eq!(acc.funds_held, Decimal::new(0, 0));
+            assert_eq!(acc.funds_total, Decimal::new(total, 0));
+            assert!(ledger.verify_conservation().is_empty());
+        }
+    }
+
+    #[test]
+    fn settle_f64_balances() {
+        let txs = [
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id: 1,
+                amount: Some(10.5),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Withdrawal,
+                client_id: 1,
+                tx_id: 2,
+                amount: Some(2.5),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id: 3,
+                amount: Some(4.0),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Dispute,
+                client_id: 1,
+                tx_id: 3,
+                amount: None,
+                timestamp: None,
+            },
+        ];
+
+        let mut ledger = Ledger::<f64>::new();
+
+        for tx in &txs {
+            assert_eq!(ledger.settle(tx), Ok(()));
+        }
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available, 8.0);
+        assert_eq!(acc.funds_held, 4.0);
+        assert_eq!(acc.funds_total, 12.0);
+    }
+
+    #[test]
+    fn reject_dispute_cycles_over_limit() {
+        let deposit_tx = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::new(500, 0)),
+            timestamp: None,
+        };
+
+        let dispute_tx = Transaction {
+            r#type: TransactionType::Dispute,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            timestamp: None,
+        };
+
+        let resolve_tx = Transaction {
+            r#type: TransactionType::Resolve,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            timestamp: None,
+        };
+
+        let mut ledger = Ledger::with_policy(SettlementPolicy {
+            max_dispute_cycles: Some(1),
+            ..Default::default()
+        });
+
+        assert_eq!(ledger.settle(&deposit_tx), Ok(()));
+        assert_eq!(ledger.settle(&dispute_tx), Ok(()));
+        assert_eq!(ledger.settle(&resolve_tx), Ok(()));
+
+        assert_eq!(
+            ledger.settle(&dispute_tx),
+            Err(PaymentError::DisputeCycleLimit {
+                tx_id: 1,
+                max_cycles: 1,
+            })
+        );
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available, Decimal::new(500, 0));
+        assert_eq!(acc.funds_held, Decimal::new(0, 0));
+    }
+
+    #[test]
+    fn flag_anomalous_transactions() {
+        let mut ledger = Ledger::with_policy(SettlementPolicy {
+            anomaly_factor: Some(Decimal::new(5, 0)),
+            ..Default::default()
+        });
+
+        let amounts = [
+            Decimal::new(10, 0),
+            Decimal::new(20, 0),
+            Decimal::new(15, 0),
+            Decimal::new(500, 0),
+        ];
+
+        for (tx_id, amount) in (1..).zip(amounts) {
+            let deposit_tx = Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id,
+                amount: Some(amount),
+                timestamp: None,
+            };
+
+            assert_eq!(ledger.settle(&deposit_tx), Ok(()));
+        }
+
+        assert_eq!(
+            ledger.warnings,
+            vec![PaymentError::Anomaly {
+                tx_id: 4,
+                amount: Decimal::new(500, 0),
+                average: Decimal::new(15, 0),
+            }]
+        );
+
+        // The anomalous deposit is still applied
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_total, Decimal::new(545, 0));
+    }
+
+    #[test]
+    fn iterate_accounts_in_client_order() {
+        let client_ids = [42, 7, 1000, 3, 65535, 0, 19];
+
+        for _ in 0..10 {
+            let mut ledger = Ledger::new();
+
+            for (tx_id, client_id) in (1..).zip(client_ids) {
+                let deposit_tx = Transaction {
+                    r#type: TransactionType::Deposit,
+                    client_id,
+                    tx_id,
+                    amount: Some(Decimal::new(1, 0)),
+                    timestamp: None,
+                };
+
+                assert_eq!(ledger.settle(&deposit_tx), Ok(()));
+            }
+
+            let ids: Vec<u16> = ledger.iter_sorted().map(|(id, _)| *id).collect();
+
+            assert_eq!(ids, [0, 3, 7, 19, 42, 1000, 65535]);
+        }
+    }
+
+    #[test]
+    fn reject_resolves_exceeding_partial_disputes() {
+        let deposit_tx = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::new(100, 0)),
+            timestamp: None,
+        };
+
+        let mut ledger = Ledger::new();
+
+        assert_eq!(ledger.settle(&deposit_tx), Ok(()));
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 1, 1, Decimal::new(30, 0))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 1, 1, Decimal::new(50, 0))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Resolve, 1, 1, Decimal::new(30, 0))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Resolve, 1, 1, Decimal::new(40, 0))),
+            Ok(())
+        );
+
+        // Only 10 of the 80 disputed remains held
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Resolve, 1, 1, Decimal::new(20, 0))),
+            Err(PaymentError::ExcessiveResolve { tx_id: 1 })
+        );
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available, Decimal::new(90, 0));
+        assert_eq!(acc.funds_held, Decimal::new(10, 0));
+        assert_eq!(acc.funds_total, Decimal::new(100, 0));
+        assert_eq!(ledger.open_disputes(), [1]);
+    }
+
+    #[test]
+    fn reject_disputes_over_account_limit() {
+        let mut ledger = Ledger::with_policy(SettlementPolicy {
+            max_disputes_per_account: Some(2),
+            ..Default::default()
+        });
+
+        for tx_id in 1..=3 {
+            let deposit_tx = Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+            };
+
+            assert_eq!(ledger.settle(&deposit_tx), Ok(()));
+        }
+
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 1, 1, None)),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 1, 2, None)),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 1, 3, None)),
+            Err(PaymentError::AccountDisputeLimit {
+                tx_id: 3,
+                client_id: 1,
+                max_disputes: 2,
+            })
+        );
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_held, Decimal::new(20, 0));
+    }
+
+    #[test]
+    fn verify_conservation_of_funds() {
+        let mut ledger = Ledger::new();
+
+        for tx in [
+            tx(TransactionType::Deposit, 1, 1, Decimal::new(100, 0)),
+            tx(TransactionType::Deposit, 1, 2, Decimal::new(50, 0)),
+            tx(TransactionType::Withdrawal, 1, 3, Decimal::new(30, 0)),
+            tx(TransactionType::Withdrawal, 1, 4, Decimal::new(500, 0)),
+            tx(TransactionType::Dispute, 1, 2, Decimal::new(20, 0)),
+            tx(TransactionType::Chargeback, 1, 2, None),
+            tx(TransactionType::Authorize, 1, 5, Decimal::new(10, 0)),
+            tx(TransactionType::Authorize, 1, 6, Decimal::new(7, 0)),
+            tx(TransactionType::Expire, 1, 6, None),
+        ] {
+            let _ = ledger.settle(&tx);
+        }
+
+        assert!(ledger.verify_conservation().is_empty());
+
+        // Crediting the account outside of any transaction breaks the identity
+        ledger
+            .accounts
+            .get_mut(&1)
+            .expect("Client 1 should exist in ledger")
+            .funds_total += Decimal::new(1, 0);
+
+        assert_eq!(
+            ledger.verify_conservation(),
+            [PaymentError::ConservationViolation {
+                client_id: 1,
+                expected: Decimal::new(100, 0),
+                total: Decimal::new(101, 0),
+            }]
+        );
+    }
+
+    #[test]
+    fn charge_fees_by_transaction_type() {
+        let mut ledger = Ledger::with_policy(SettlementPolicy {
+            fees: HashMap::from([
+                (TransactionType::Deposit, Decimal::new(10, 2)),
+                (TransactionType::Withdrawal, Decimal::new(25, 2)),
+            ]),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Deposit, 1, 1, Decimal::new(1000, 2))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Withdrawal, 1, 2, Decimal::new(500, 2))),
+            Ok(())
+        );
+
+        // 4.60 is still available, but not along with the 0.25 fee
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Withdrawal, 1, 3, Decimal::new(460, 2))),
+            Err(PaymentError::InsufficientFunds { tx_id: 3 })
+        );
+
+        // 0.05 deposited can't pay the 0.10 fee
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Deposit, 1, 4, Decimal::new(5, 2))),
+            Err(PaymentError::DepositBelowFee { tx_id: 4 })
+        );
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available, Decimal::new(465, 2));
+        assert_eq!(acc.funds_total, Decimal::new(465, 2));
+        assert_eq!(
+            ledger.fee_totals,
+            HashMap::from([
+                (TransactionType::Deposit, Decimal::new(10, 2)),
+                (TransactionType::Withdrawal, Decimal::new(25, 2)),
+            ])
+        );
+        assert!(ledger.verify_conservation().is_empty());
+    }
+
+    #[test]
+    fn clamp_disputes_within_tolerance() {
+        let deposit_tx = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::new(10, 0)),
+            timestamp: None,
+        };
+
+        let policy = SettlementPolicy {
+            dispute_tolerance: Some(Decimal::new(1, 2)),
+            ..Default::default()
+        };
+
+        let mut ledger = Ledger::with_policy(policy.clone());
+
+        assert_eq!(ledger.settle(&deposit_tx), Ok(()));
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 1, 1, Decimal::new(10005, 3))),
+            Ok(())
+        );
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available, Decimal::new(0, 0));
+        assert_eq!(acc.funds_held, Decimal::new(10, 0));
+
+        let mut ledger = Ledger::with_policy(policy);
+
+        assert_eq!(ledger.settle(&deposit_tx), Ok(()));
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 1, 1, Decimal::new(1002, 2))),
+            Err(PaymentError::ExcessiveDispute { tx_id: 1 })
+        );
+    }
+
+    #[test]
+    fn reject_disputes_over_total_held_limit() {
+        let mut ledger = Ledger::with_policy(SettlementPolicy {
+            max_total_held: Some(Decimal::new(15, 0)),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Deposit, 1, 1, Decimal::new(10, 0))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Deposit, 2, 2, Decimal::new(10, 0))),
+            Ok(())
+        );
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 1, 1, None)),
+            Ok(())
+        );
+
+        // Holding another 10 would take the accounts to 20 held in total
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 2, 2, None)),
+            Err(PaymentError::HeldLimit {
+                tx_id: 2,
+                max_total_held: Decimal::new(15, 0),
+            })
+        );
+
+        let resolve_tx = tx(TransactionType::Resolve, 1, 1, None);
+
+        assert_eq!(ledger.settle(&resolve_tx), Ok(()));
+        assert_eq!(
+            ledger.settle(&tx(TransactionType::Dispute, 2, 2, None)),
+            Ok(())
+        );
+    }
+}
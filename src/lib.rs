@@ -0,0 +1,2090 @@
+pub mod account;
+pub mod amount;
+pub mod bundle;
+pub mod config;
+pub mod error;
+pub mod latency;
+pub mod ledger;
+#[cfg(feature = "parquet")]
+pub mod parquet_output;
+pub mod profile;
+pub mod series;
+pub mod state;
+pub mod transaction;
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::thread;
+
+use config::{Config, EmptyClientPolicy, InputFormat};
+use csv::{ReaderBuilder, Trim, WriterBuilder};
+use flate2::read::GzDecoder;
+use memmap2::Mmap;
+use rust_decimal::Decimal;
+
+use crate::account::Account;
+use crate::amount::{Amount, Balance};
+use crate::error::PaymentError;
+use crate::ledger::{AccountsDB, Ledger, TransactionsDB};
+use crate::transaction::{RefRow, Transaction, TransactionRecord, TransactionRow};
+
+pub fn process_csv(
+    config: &Config,
+    warnings: &mut Vec<PaymentError>,
+) -> Result<VecDeque<Transaction<Balance>>, Box<dyn Error>> {
+    let path = &config.transactions_path;
+    let is_gzip = path.ends_with(".gz");
+
+    let file = File::open(path).map_err(|e| PaymentError::Io {
+        path: path.clone(),
+        message: e.to_string(),
+    })?;
+
+    // Reads fall back to the file itself whenever it can't be mapped, e.g. when it's a pipe.
+    // Safety: the mapping is only read, and the input is assumed not to change while it's processed.
+    let mapped = config
+        .mmap
+        .then(|| unsafe { Mmap::map(&file) }.ok())
+        .flatten();
+
+    let result = match (&mapped, is_gzip) {
+        (Some(mapped), true) => process_reader(GzDecoder::new(&mapped[..]), config, warnings),
+        (Some(mapped), false) => process_reader(&mapped[..], config, warnings),
+        (None, true) => process_reader(GzDecoder::new(file), config, warnings),
+        (None, false) => process_reader(file, config, warnings),
+    };
+
+    // Decoder failures surface as I/O errors from the CSV reader, so name the file they came from
+    result.map_err(
+        |e| match e.downcast_ref::<csv::Error>().map(csv::Error::kind) {
+            Some(csv::ErrorKind::Io(io_err)) => {
+                let message = if is_gzip {
+                    format!("invalid or truncated gzip data ({io_err})")
+                } else {
+                    io_err.to_string()
+                };
+
+                PaymentError::Io {
+                    path: path.clone(),
+                    message,
+                }
+                .into()
+            }
+            _ => e,
+        },
+    )
+}
+
+pub fn process_reader<R: io::Read>(
+    input: R,
+    config: &Config,
+    warnings: &mut Vec<PaymentError>,
+) -> Result<VecDeque<Transaction<Balance>>, Box<dyn Error>> {
+    match config.input_format {
+        InputFormat::Csv => {
+            let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+
+            // Read the headers up front, the deserializing iterator would otherwise swallow read
+            // errors
+            let headers = reader.headers()?;
+
+            for &column in config.input_version.columns() {
+                if !headers.iter().any(|header| header == column) {
+                    return Err(PaymentError::MissingColumn { column }.into());
+                }
+            }
+
+            let rows = reader.deserialize().map(|row| row.map_err(Into::into));
+
+            process_rows(rows, config, warnings)
+        }
+        InputFormat::Json => {
+            let rows: Vec<TransactionRow> = serde_json::from_reader(input)?;
+
+            process_rows(rows.into_iter().map(Ok), config, warnings)
+        }
+    }
+}
+
+fn process_rows(
+    rows: impl Iterator<Item = Result<TransactionRow, Box<dyn Error>>>,
+    config: &Config,
+    warnings: &mut Vec<PaymentError>,
+) -> Result<VecDeque<Transaction<Balance>>, Box<dyn Error>> {
+    let mut unprocessed_transactions = VecDeque::new();
+
+    for row in rows {
+        let row = row?;
+        let tx_id = row.tx_id;
+
+        if let (Some(max_scale), Some(amount)) = (config.max_input_scale, row.amount) {
+            if amount.scale() > max_scale {
+                warnings.push(PaymentError::ExcessiveScale {
+                    tx_id,
+                    scale: amount.scale(),
+                    max_scale,
+                });
+
+                continue;
+            }
+        }
+
+        match row.into_transaction(&config.type_codes) {
+            Ok(tx) => unprocessed_transactions.push_back(tx),
+            Err(e @ PaymentError::EmptyClient { .. })
+                if config.empty_client == EmptyClientPolicy::Skip =>
+            {
+                warnings.push(e)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if config.detect_duplicate_file {
+        warnings.extend(detect_duplicate_block(&unprocessed_transactions));
+    }
+
+    Ok(unprocessed_transactions)
+}
+
+/// Reads and settles each reader on its own thread, then merges the resulting ledgers. Clients may
+/// appear in several readers, but each reader is settled on its own before their balances are
+/// added, so e.g. a withdrawal can't draw on funds deposited in another reader. Disputes, resolves
+/// and chargebacks must be in the same reader as the transaction they reference. Parse warnings,
+/// rejected transactions and tx ids settled by several readers are added to `warnings`.
+pub fn process_readers<R: io::Read + Send>(
+    readers: Vec<R>,
+    config: &Config,
+    warnings: &mut Vec<PaymentError>,
+) -> Result<AccountsDB<Balance>, Box<dyn Error>> {
+    let results = thread::scope(|scope| {
+        let handles: Vec<_> = readers
+            .into_iter()
+            .map(|reader| {
+                scope.spawn(move || {
+                    let mut warnings = Vec::new();
+
+                    // Boxed errors can't be sent between threads, so only their message is kept
+                    let txs =
+                        process_reader(reader, config, &mut warnings).map_err(|e| e.to_string())?;
+
+                    let ledger = process_ledger(Ledger::with_policy(config.policy.clone()), txs);
+
+                    Ok::<_, String>((ledger, warnings))
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("reader thread should not panic"))
+            .collect::<Vec<_>>()
+    });
+
+    let mut merged = Ledger::with_policy(config.policy.clone());
+
+    for result in results {
+        let (ledger, reader_warnings) = result?;
+
+        warnings.extend(reader_warnings);
+        merged.merge(ledger);
+    }
+
+    warnings.append(&mut merged.errors);
+    warnings.append(&mut merged.warnings);
+
+    Ok(merged.accounts)
+}
+
+/// Looks for the longest contiguous block of rows which exactly repeat earlier rows, reporting it
+/// when it makes up at least half of the input. A file concatenated with itself is one such block.
+fn detect_duplicate_block<A: Amount>(txs: &VecDeque<Transaction<A>>) -> Option<PaymentError> {
+    let mut seen = HashSet::new();
+    let mut longest = (0, 0);
+    let mut current = (0, 0);
+
+    for tx in txs {
+        if seen.insert((
+            tx.r#type,
+            tx.client_id,
+            tx.tx_id,
+            tx.amount.map(Amount::to_decimal),
+        )) {
+            current = (0, 0);
+            continue;
+        }
+
+        if current.1 == 0 {
+            current.0 = tx.tx_id;
+        }
+        current.1 += 1;
+
+        if current.1 > longest.1 {
+            longest = current;
+        }
+    }
+
+    let (first_tx_id, rows) = longest;
+
+    (rows > 0 && rows * 2 >= txs.len())
+        .then_some(PaymentError::DuplicateBlock { first_tx_id, rows })
+}
+
+// The account and reference transaction data stores are created inside this function for ease-of-use
+// In a real-world system, connections to these external data sources would be passed in via
+// parameters if needed
+pub fn process_transactions<A: Amount>(
+    unprocessed_transactions: VecDeque<Transaction<A>>,
+) -> Ledger<A> {
+    process_ledger(Ledger::new(), unprocessed_transactions)
+}
+
+/// Sorts the transactions with `cmp` before processing them, e.g. to make order-sensitive dispute
+/// handling deterministic. The sort is stable, so transactions comparing equal keep their order.
+pub fn process_transactions_ordered<A: Amount, F>(
+    mut unprocessed_transactions: VecDeque<Transaction<A>>,
+    cmp: F,
+) -> Ledger<A>
+where
+    F: FnMut(&Transaction<A>, &Transaction<A>) -> Ordering,
+{
+    unprocessed_transactions.make_contiguous().sort_by(cmp);
+
+    process_transactions(unprocessed_transactions)
+}
+
+/// Processes transactions into an existing ledger, e.g. one created with a non-default policy
+pub fn process_ledger<A: Amount>(
+    ledger: Ledger<A>,
+    unprocessed_transactions: VecDeque<Transaction<A>>,
+) -> Ledger<A> {
+    process_with(ledger, unprocessed_transactions, |_, _| {})
+}
+
+/// Processes transactions like `process_ledger`, invoking `on_finalize` for each account as soon
+/// as none of the remaining transactions belong to it, so results can be streamed out before the
+/// whole batch is finished
+pub fn process_with<A: Amount, F: FnMut(&u16, &Account<A>)>(
+    ledger: Ledger<A>,
+    unprocessed_transactions: VecDeque<Transaction<A>>,
+    on_finalize: F,
+) -> Ledger<A> {
+    process_with_steps(ledger, unprocessed_transactions, |_, _| {}, on_finalize)
+}
+
+/// Processes transactions like `process_with`, additionally invoking `on_settle` with the client's
+/// account right after each transaction is applied to it
+pub fn process_with_steps<A, S, F>(
+    ledger: Ledger<A>,
+    unprocessed_transactions: VecDeque<Transaction<A>>,
+    on_settle: S,
+    on_finalize: F,
+) -> Ledger<A>
+where
+    A: Amount,
+    S: FnMut(&Transaction<A>, &Account<A>),
+    F: FnMut(&u16, &Account<A>),
+{
+    process_with_checkpoints(
+        ledger,
+        unprocessed_transactions,
+        VecDeque::new(),
+        None,
+        on_settle,
+        on_finalize,
+        |_, _, _| {},
+    )
+}
+
+/// Processes transactions like `process_with_steps`, with `requeued` being transactions which
+/// were requeued by an earlier run and are retried after the input. `on_checkpoint` is invoked
+/// each time another `checkpoint_every` transactions have been applied or rejected, with the
+/// ledger, the number of input transactions taken off the queue so far and the transactions
+/// requeued since, e.g. to save the state so processing can be resumed later.
+pub fn process_with_checkpoints<A, S, F, C>(
+    mut ledger: Ledger<A>,
+    mut input: VecDeque<Transaction<A>>,
+    mut requeued: VecDeque<Transaction<A>>,
+    checkpoint_every: Option<usize>,
+    mut on_settle: S,
+    mut on_finalize: F,
+    mut on_checkpoint: C,
+) -> Ledger<A>
+where
+    A: Amount,
+    S: FnMut(&Transaction<A>, &Account<A>),
+    F: FnMut(&u16, &Account<A>),
+    C: FnMut(&Ledger<A>, usize, &VecDeque<Transaction<A>>),
+{
+    let mut remaining = HashMap::<u16, usize>::new();
+    for tx in input.iter().chain(&requeued) {
+        *remaining.entry(tx.client_id).or_default() += 1;
+    }
+
+    // Transactions requeued since one last settled. Once every queued transaction has been
+    // requeued without any settling, the rest reference transactions which will never arrive.
+    let mut stalled = 0;
+    let mut handled = 0;
+    let mut consumed = 0;
+
+    // Requeued transactions go behind the whole input, so the input is taken first
+    loop {
+        let tx = match input.pop_front() {
+            Some(tx) => {
+                consumed += 1;
+                tx
+            }
+            None => match requeued.pop_front() {
+                Some(tx) => tx,
+                None => break,
+            },
+        };
+
+        match ledger.settle(&tx) {
+            Ok(()) => {
+                stalled = 0;
+
+                let acc = ledger
+                    .accounts
+                    .get(&tx.client_id)
+                    .expect("account should exist");
+
+                on_settle(&tx, acc);
+            }
+            Err(PaymentError::UnknownReference { .. })
+                if stalled <= input.len() + requeued.len() =>
+            {
+                stalled += 1;
+                requeued.push_back(tx);
+                continue;
+            }
+            Err(e) => ledger.errors.push(e),
+        }
+
+        let client_remaining = remaining
+            .get_mut(&tx.client_id)
+            .expect("client should have remaining transactions");
+        *client_remaining -= 1;
+
+        if *client_remaining == 0 {
+            let acc = ledger
+                .accounts
+                .get(&tx.client_id)
+                .expect("account should exist");
+
+            on_finalize(&tx.client_id, acc);
+        }
+
+        handled += 1;
+
+        if checkpoint_every.is_some_and(|every| handled % every == 0) {
+            on_checkpoint(&ledger, consumed, &requeued);
+        }
+    }
+
+    ledger
+}
+
+/// Fails when `--require-disputes-resolved` is set and any disputes are still open
+pub fn check_open_disputes<A: Amount>(
+    ledger: &Ledger<A>,
+    config: &Config,
+) -> Result<(), PaymentError> {
+    if !config.require_disputes_resolved {
+        return Ok(());
+    }
+
+    let tx_ids = ledger.open_disputes();
+
+    if tx_ids.is_empty() {
+        Ok(())
+    } else {
+        Err(PaymentError::OpenDisputes { tx_ids })
+    }
+}
+
+/// Runs the full settlement pipeline over `txs`, returning the finalized accounts
+pub fn accounts_from_transactions<A: Amount>(
+    txs: impl IntoIterator<Item = Transaction<A>>,
+) -> AccountsDB<A> {
+    process_transactions(txs.into_iter().collect()).accounts
+}
+
+pub fn write_output<A: Amount, W: io::Write>(
+    accounts: AccountsDB<A>,
+    config: &Config,
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    match write_rows(accounts, config, out) {
+        // The reader going away (e.g. `head`) isn't a failure, much like for other Unix tools
+        Err(e) if config.tolerate_broken_pipe && is_broken_pipe(e.as_ref()) => Ok(()),
+        result => result,
+    }
+}
+
+fn write_rows<A: Amount, W: io::Write>(
+    accounts: AccountsDB<A>,
+    config: &Config,
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = WriterBuilder::new().from_writer(out);
+
+    if let Some(profile) = config.output_profile {
+        writer.write_record(profile.columns.iter().map(|(_, header)| header))?;
+    }
+
+    for (rows, (_, acc)) in accounts.into_iter().enumerate() {
+        match config.output_profile {
+            Some(profile) => {
+                writer.write_record(profile.columns.iter().map(|(column, _)| column.value(&acc)))?
+            }
+            None => writer.serialize(acc)?,
+        }
+
+        // Flushing only between rows means an interrupted stream never ends on a partial record
+        if let Some(flush_every) = config.flush_every {
+            if (rows + 1) % flush_every == 0 {
+                writer.flush()?;
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+fn is_broken_pipe(e: &(dyn Error + 'static)) -> bool {
+    let io_err = match e.downcast_ref::<csv::Error>().map(csv::Error::kind) {
+        Some(csv::ErrorKind::Io(io_err)) => Some(io_err),
+        _ => e.downcast_ref::<io::Error>(),
+    };
+
+    io_err.is_some_and(|io_err| io_err.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// How much rounding changed the sum of each balance across all accounts
+#[derive(Debug, Default, PartialEq)]
+pub struct RoundingAdjustment {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub pending: Decimal,
+    pub total: Decimal,
+}
+
+impl Display for RoundingAdjustment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "available {}, held {}, pending {}, total {}",
+            self.available, self.held, self.pending, self.total
+        )
+    }
+}
+
+/// Rounds every account's balances to `dp` decimal places, returning how much the sum of each
+/// balance changed by so downstream ledgers can account for the difference
+pub fn round_accounts<A: Amount>(accounts: &mut AccountsDB<A>, dp: u32) -> RoundingAdjustment {
+    let mut adjustment = RoundingAdjustment::default();
+
+    for acc in accounts.values_mut() {
+        let before = [
+            acc.funds_available,
+            acc.funds_held,
+            acc.funds_pending,
+            acc.funds_total,
+        ];
+
+        acc.round_balances(dp);
+
+        let after = [
+            acc.funds_available,
+            acc.funds_held,
+            acc.funds_pending,
+            acc.funds_total,
+        ];
+        let sums = [
+            &mut adjustment.available,
+            &mut adjustment.held,
+            &mut adjustment.pending,
+            &mut adjustment.total,
+        ];
+
+        for ((sum, before), after) in sums.into_iter().zip(before).zip(after) {
+            *sum += after.to_decimal() - before.to_decimal();
+        }
+    }
+
+    adjustment
+}
+
+/// Drops accounts which never had a deposit, withdrawal or capture applied, e.g. clients whose only
+/// transactions were rejected withdrawals
+pub fn retain_active<A: Amount>(accounts: &mut AccountsDB<A>) {
+    accounts.retain(|_, acc| acc.had_successful_activity);
+}
+
+/// Returns the smallest subset of `txs`, in their original order, which settles `target_client`'s
+/// account the same way: the client's own transactions, the transactions its disputes, resolves
+/// and chargebacks reference, and other clients' transactions reusing its tx ids, which can get
+/// its transactions rejected as duplicates.
+pub fn minimize<A: Amount>(
+    txs: impl IntoIterator<Item = Transaction<A>>,
+    target_client: u16,
+) -> Vec<Transaction<A>> {
+    let txs: Vec<_> = txs.into_iter().collect();
+
+    let tx_ids: HashSet<u32> = txs
+        .iter()
+        .filter(|tx| tx.client_id == target_client)
+        .map(|tx| tx.tx_id)
+        .collect();
+
+    txs.into_iter()
+        .filter(|tx| {
+            tx.client_id == target_client
+                || (tx.r#type.is_referenceable() && tx_ids.contains(&tx.tx_id))
+        })
+        .collect()
+}
+
+/// Splits off transactions dated after `cutoff`, returning the ones to process and the future ones.
+/// Transactions without a timestamp are processed.
+pub fn split_as_of<A: Amount>(
+    txs: VecDeque<Transaction<A>>,
+    cutoff: u64,
+) -> (VecDeque<Transaction<A>>, VecDeque<Transaction<A>>) {
+    txs.into_iter()
+        .partition(|tx| tx.timestamp.is_none_or(|timestamp| timestamp <= cutoff))
+}
+
+/// Hashes every account in client order, with balances normalized so e.g. `1.50` and `1.5` hash
+/// the same. Uses 64-bit FNV-1a, which unlike the standard library's hasher is stable across runs
+/// and Rust versions.
+pub fn result_hash<A: Amount>(accounts: &AccountsDB<A>) -> u64 {
+    let mut clients: Vec<_> = accounts.keys().collect();
+    clients.sort_unstable();
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+
+    for client_id in clients {
+        let acc = &accounts[client_id];
+        let line = format!(
+            "{},{},{},{},{},{}\n",
+            acc.client_id,
+            acc.funds_available.to_decimal().normalize(),
+            acc.funds_held.to_decimal().normalize(),
+            acc.funds_pending.to_decimal().normalize(),
+            acc.funds_total.to_decimal().normalize(),
+            acc.locked
+        );
+
+        for byte in line.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    hash
+}
+
+/// Splits off accounts with a negative balance, returning the clean and quarantined accounts
+pub fn split_quarantined<A: Amount>(accounts: AccountsDB<A>) -> (AccountsDB<A>, AccountsDB<A>) {
+    accounts
+        .into_iter()
+        .partition(|(_, acc)| !acc.has_negative_balance())
+}
+
+/// Writes every retained reference transaction in the input CSV format along with its lifecycle,
+/// ordered by tx id
+pub fn write_refs<A: Amount, W: io::Write>(
+    ref_txs: &TransactionsDB<A>,
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = WriterBuilder::new().from_writer(out);
+
+    let mut records: Vec<_> = ref_txs.values().collect();
+    records.sort_by_key(|record| record.tx.tx_id);
+
+    for record in records {
+        writer.serialize(RefRow::new(record))?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads accounts in the output CSV format, e.g. the output of an earlier run
+pub fn read_accounts<R: io::Read>(input: R) -> Result<AccountsDB<Balance>, Box<dyn Error>> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+
+    let mut accounts = AccountsDB::new();
+
+    for result in reader.deserialize() {
+        let mut acc: Account<Balance> = result?;
+
+        // The output doesn't say whether anything was applied, but it was settled by an earlier run
+        acc.had_successful_activity = true;
+        accounts.insert(acc.client_id, acc);
+    }
+
+    Ok(accounts)
+}
+
+/// Reads reference transactions written by `write_refs`, or rows in the input CSV format which
+/// haven't been referenced yet
+pub fn read_refs<R: io::Read>(input: R) -> Result<Vec<TransactionRecord<Balance>>, Box<dyn Error>> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+
+    let mut records = Vec::new();
+
+    for result in reader.deserialize() {
+        let row: RefRow = result?;
+        records.push(row.into_record());
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use rust_decimal::Decimal;
+
+    use crate::{
+        account::Account,
+        accounts_from_transactions,
+        amount::{Amount, Balance},
+        check_open_disputes,
+        config::{Config, EmptyClientPolicy},
+        error::PaymentError,
+        ledger::{AccountsDB, Ledger, SettlementPolicy},
+        minimize, process_csv, process_ledger, process_reader, process_readers,
+        process_transactions, process_transactions_ordered, process_with, read_accounts, read_refs,
+        result_hash, retain_active, round_accounts, split_as_of, split_quarantined,
+        transaction::{Transaction, TransactionType},
+        write_output, write_refs, RoundingAdjustment,
+    };
+
+    /// Builds the command line arguments `Config::new` takes, for the tests of every module
+    pub(crate) fn args_from(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    pub(crate) fn config_from(args: &[&str]) -> Config {
+        Config::new(&args_from(args))
+    }
+
+    /// Reads `input` with the configuration `args` give, returning its transactions along with the
+    /// warnings raised while reading it, for the tests of every module
+    pub(crate) fn read(
+        input: &str,
+        args: &[&str],
+    ) -> (VecDeque<Transaction<Balance>>, Vec<PaymentError>) {
+        let mut warnings = Vec::new();
+        let txs = process_reader(input.as_bytes(), &config_from(args), &mut warnings)
+            .expect("input should be read");
+
+        (txs, warnings)
+    }
+
+    /// Reads `input` like `read` and settles it with the default policy
+    fn run(input: &str, args: &[&str]) -> Ledger<Balance> {
+        let (txs, _) = read(input, args);
+
+        process_transactions(txs)
+    }
+
+    /// Builds an undated transaction, for the tests of every module
+    pub(crate) fn tx(
+        r#type: TransactionType,
+        client_id: u16,
+        tx_id: u32,
+        amount: impl Into<Option<Decimal>>,
+    ) -> Transaction {
+        Transaction {
+            r#type,
+            client_id,
+            tx_id,
+            amount: amount.into(),
+            timestamp: None,
+        }
+    }
+
+    /// Records how many lines had been written each time it was flushed
+    struct CountingWriter {
+        lines: usize,
+        flushes: Vec<usize>,
+    }
+
+    impl io::Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.lines += buf.iter().filter(|b| **b == b'\n').count();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.push(self.lines);
+            Ok(())
+        }
+    }
+
+    /// Fails every write as if the reading end of a pipe had been closed
+    struct ClosedPipe;
+
+    impl io::Write for ClosedPipe {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn process_basic_transactions() {
+        let deposit1 = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::new(10, 1)),
+            timestamp: None,
+        };
+
+        let deposit2 = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 2,
+            tx_id: 2,
+            amount: Some(Decimal::new(20, 1)),
+            timestamp: None,
+        };
+
+        let deposit3 = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 3,
+            amount: Some(Decimal::new(20, 1)),
+            timestamp: None,
+        };
+
+        let withdrawal1 = Transaction {
+            r#type: TransactionType::Withdrawal,
+            client_id: 1,
+            tx_id: 4,
+            amount: Some(Decimal::new(15, 1)),
+            timestamp: None,
+        };
+
+        let withdrawal2 = Transaction {
+            r#type: TransactionType::Withdrawal,
+            client_id: 2,
+            tx_id: 5,
+            amount: Some(Decimal::new(30, 1)),
+            timestamp: None,
+        };
+
+        let unprocessed_transactions =
+            VecDeque::<Transaction>::from([deposit1, deposit2, deposit3, withdrawal1, withdrawal2]);
+
+        let ledger = process_transactions(unprocessed_transactions);
+
+        let client1 = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in finalized accounts");
+
+        assert_eq!(client1.funds_available, Decimal::new(15, 1));
+        assert_eq!(client1.funds_held, Decimal::new(0, 0));
+        assert_eq!(client1.funds_total, Decimal::new(15, 1));
+        assert!(!client1.locked);
+
+        let client2 = ledger
+            .accounts
+            .get(&2)
+            .expect("Client 2 should exist in finalized accounts");
+
+        assert_eq!(client2.funds_available, Decimal::new(2, 0));
+        assert_eq!(client2.funds_held, Decimal::new(0, 0));
+        assert_eq!(client2.funds_total, Decimal::new(2, 0));
+        assert!(!client2.locked);
+    }
+
+    #[test]
+    fn process_complex_transactions() {
+        let deposit1 = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::new(500_0005, 4)),
+            timestamp: None,
+        };
+
+        let deposit2 = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 2,
+            amount: Some(Decimal::new(1000, 0)),
+            timestamp: None,
+        };
+
+        let dispute1 = Transaction {
+            r#type: TransactionType::Dispute,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            timestamp: None,
+        };
+
+        let resolve1 = Transaction {
+            r#type: TransactionType::Resolve,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            timestamp: None,
+        };
+
+        let deposit3 = Transaction {
+            r#type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 3,
+            amount: Some(Decimal::new(100, 0)),
+            timestamp: None,
+        };
+
+        let dispute2 = Transaction {
+            r#type: TransactionType::Dispute,
+            client_id: 1,
+            tx_id: 3,
+            amount: None,
+            timestamp: None,
+        };
+
+        let chargeback1 = Transaction {
+            r#type: TransactionType::Chargeback,
+            client_id: 1,
+            tx_id: 3,
+            amount: None,
+            timestamp: None,
+        };
+
+        let txs = VecDeque::<Transaction>::from([
+            deposit1,
+            deposit2,
+            dispute1,
+            resolve1,
+            deposit3,
+            dispute2,
+            chargeback1,
+        ]);
+
+        let ledger = process_transactions(txs);
+
+        let client1 = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in finalized accounts");
+
+        assert_eq!(client1.funds_available, Decimal::new(1500_0005, 4));
+        assert_eq!(client1.funds_held, Decimal::new(0, 0));
+        assert_eq!(client1.funds_total, Decimal::new(1500_0005, 4));
+        assert!(client1.locked);
+    }
+
+    #[test]
+    fn flush_output_every_n_rows() {
+        let accounts: AccountsDB = (1..=5).map(|id| (id, Account::new(id))).collect();
+
+        let config = config_from(&["csv-payments", "--flush-every", "2", "transactions.csv"]);
+
+        let mut out = CountingWriter {
+            lines: 0,
+            flushes: Vec::new(),
+        };
+
+        write_output(accounts, &config, &mut out).expect("output should be written");
+
+        // Header plus two rows, then two more rows, then the final flush once all rows are written
+        assert_eq!(&out.flushes[..3], &[3, 5, 6]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown option --flush-evry.")]
+    fn reject_unknown_flags() {
+        config_from(&["csv-payments", "--flush-evry", "2", "transactions.csv"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "only one transactions file can be given")]
+    fn reject_second_transactions_file() {
+        config_from(&["csv-payments", "transactions.csv", "more.csv"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "--verify-conservation can't be combined with --import-state.")]
+    fn reject_conservation_check_of_imported_state() {
+        config_from(&[
+            "csv-payments",
+            "--verify-conservation",
+            "--import-state",
+            "accounts.csv",
+            "transactions.csv",
+        ]);
+    }
+
+    #[test]
+    fn read_empty_client_rows() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, , 2, 2.0\n";
+
+        let (txs, warnings) = read(
+            input,
+            &["csv-payments", "--empty-client", "skip", "transactions.csv"],
+        );
+
+        assert_eq!(txs.len(), 1);
+        assert_eq!(warnings, vec![PaymentError::EmptyClient { tx_id: 2 }]);
+
+        let config = config_from(&[
+            "csv-payments",
+            "--empty-client",
+            "error",
+            "transactions.csv",
+        ]);
+        let mut warnings = Vec::new();
+
+        let result = process_reader(input.as_bytes(), &config, &mut warnings);
+
+        assert!(result.is_err());
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn accounts_from_transaction_iterator() {
+        let accounts = accounts_from_transactions([
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id: 1,
+                amount: Some(Decimal::new(50, 1)),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Withdrawal,
+                client_id: 1,
+                tx_id: 2,
+                amount: Some(Decimal::new(20, 1)),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 2,
+                tx_id: 3,
+                amount: Some(Decimal::new(10, 1)),
+                timestamp: None,
+            },
+        ]);
+
+        let client1 = accounts
+            .get(&1)
+            .expect("Client 1 should exist in finalized accounts");
+
+        assert_eq!(client1.funds_available, Decimal::new(3, 0));
+        assert_eq!(client1.funds_total, Decimal::new(3, 0));
+
+        let client2 = accounts
+            .get(&2)
+            .expect("Client 2 should exist in finalized accounts");
+
+        assert_eq!(client2.funds_available, Decimal::new(1, 0));
+        assert_eq!(client2.funds_total, Decimal::new(1, 0));
+    }
+
+    #[test]
+    fn dump_reference_transactions() {
+        let input = "type, client, tx, amount
+deposit, 1, 2, 5.25
+withdrawal, 1, 3, 1.5
+dispute, 1, 2,
+deposit, 2, 1, 2.5
+resolve, 1, 2,
+";
+
+        let ledger = run(input, &["csv-payments", "transactions.csv"]);
+
+        let mut out = Vec::new();
+        write_refs(&ledger.ref_txs, &mut out).expect("reference transactions should be written");
+
+        assert_eq!(
+            String::from_utf8(out).expect("dump should be valid UTF-8"),
+            "type,client,tx,amount,state,disputed,charged_back,dispute_cycles
+deposit,2,1,2.5,Processed,0,0,0
+deposit,1,2,5.25,Resolved,0,0,1
+withdrawal,1,3,1.5,Processed,0,0,0
+"
+        );
+    }
+
+    #[test]
+    fn reject_amounts_over_max_input_scale() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.05\ndeposit, 1, 2, 2.0005\n";
+
+        let (txs, warnings) = read(
+            input,
+            &["csv-payments", "--max-input-scale", "2", "transactions.csv"],
+        );
+
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].tx_id, 1);
+        assert_eq!(
+            warnings,
+            vec![PaymentError::ExcessiveScale {
+                tx_id: 2,
+                scale: 4,
+                max_scale: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn process_with_finalize_callback() {
+        let txs = VecDeque::<Transaction>::from([
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 2,
+                tx_id: 2,
+                amount: Some(Decimal::new(20, 0)),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Dispute,
+                client_id: 1,
+                tx_id: 1,
+                amount: None,
+                timestamp: None,
+            },
+        ]);
+
+        let mut finalized = Vec::new();
+
+        process_with(Ledger::new(), txs.clone(), |id, acc| {
+            finalized.push((
+                *id,
+                acc.funds_available,
+                acc.funds_held,
+                acc.funds_total,
+                acc.locked,
+            ))
+        });
+
+        let ledger = process_transactions(txs);
+
+        let mut expected: Vec<_> = ledger
+            .accounts
+            .values()
+            .map(|acc| {
+                (
+                    acc.client_id,
+                    acc.funds_available,
+                    acc.funds_held,
+                    acc.funds_total,
+                    acc.locked,
+                )
+            })
+            .collect();
+        expected.sort_by_key(|(id, ..)| *id);
+
+        // Client 2 has no transactions after its deposit, so it's finalized before client 1
+        assert_eq!(
+            finalized.iter().map(|(id, ..)| *id).collect::<Vec<_>>(),
+            [2, 1]
+        );
+
+        finalized.sort_by_key(|(id, ..)| *id);
+        assert_eq!(finalized, expected);
+    }
+
+    fn read_gzip_input(name: &str, contents: &[u8]) -> PaymentError {
+        let path = env::temp_dir().join(name);
+        fs::write(&path, contents).expect("input file should be written");
+
+        let path = path.to_string_lossy().to_string();
+        let config = config_from(&["csv-payments", &path]);
+        let mut warnings = Vec::new();
+
+        let err = process_csv(&config, &mut warnings).expect_err("input should be rejected");
+
+        *err.downcast::<PaymentError>()
+            .expect("error should be a PaymentError")
+    }
+
+    #[test]
+    fn reject_empty_gzip_input() {
+        let err = read_gzip_input("csv-payments-empty.csv.gz", &[]);
+
+        assert!(
+            matches!(err, PaymentError::Io { ref path, .. } if path.ends_with("csv-payments-empty.csv.gz"))
+        );
+    }
+
+    #[test]
+    fn reject_truncated_gzip_input() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0\n")
+            .expect("input should be compressed");
+        let compressed = encoder.finish().expect("input should be compressed");
+
+        let err = read_gzip_input(
+            "csv-payments-truncated.csv.gz",
+            &compressed[..compressed.len() / 2],
+        );
+
+        assert!(
+            matches!(err, PaymentError::Io { ref path, .. } if path.ends_with("csv-payments-truncated.csv.gz"))
+        );
+    }
+
+    #[test]
+    fn detect_input_concatenated_with_itself() {
+        let rows =
+            "deposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0\nwithdrawal, 1, 3, 0.5\ndispute, 2, 2,\n";
+        let input = format!("type, client, tx, amount\n{rows}{rows}");
+
+        let (txs, warnings) = read(
+            &input,
+            &[
+                "csv-payments",
+                "--detect-full-duplicate-file",
+                "transactions.csv",
+            ],
+        );
+
+        assert_eq!(
+            warnings,
+            vec![PaymentError::DuplicateBlock {
+                first_tx_id: 1,
+                rows: 4,
+            }]
+        );
+
+        // The repeated deposits and withdrawal are rejected rather than applied twice
+        let ledger = process_transactions(txs);
+
+        let client1 = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in finalized accounts");
+
+        assert_eq!(client1.funds_total.to_decimal(), Decimal::new(5, 1));
+        assert_eq!(
+            ledger.errors[..3],
+            [
+                PaymentError::DuplicateTransaction { tx_id: 1 },
+                PaymentError::DuplicateTransaction { tx_id: 2 },
+                PaymentError::DuplicateTransaction { tx_id: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    fn require_disputes_resolved() {
+        let txs = VecDeque::<Transaction>::from([
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id: 2,
+                amount: Some(Decimal::new(5, 0)),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Dispute,
+                client_id: 1,
+                tx_id: 1,
+                amount: None,
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Dispute,
+                client_id: 1,
+                tx_id: 2,
+                amount: None,
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Resolve,
+                client_id: 1,
+                tx_id: 2,
+                amount: None,
+                timestamp: None,
+            },
+        ]);
+
+        let ledger = process_transactions(txs);
+
+        let config = config_from(&["csv-payments", "transactions.csv"]);
+        assert_eq!(check_open_disputes(&ledger, &config), Ok(()));
+
+        let config = config_from(&[
+            "csv-payments",
+            "--require-disputes-resolved",
+            "transactions.csv",
+        ]);
+        assert_eq!(
+            check_open_disputes(&ledger, &config),
+            Err(PaymentError::OpenDisputes { tx_ids: vec![1] })
+        );
+    }
+
+    #[test]
+    fn write_bankx_profile_output() {
+        let mut acc = Account::new(7);
+        acc.funds_available = Decimal::new(15, 1);
+        acc.funds_held = Decimal::new(5, 1);
+        acc.funds_total = Decimal::new(2, 0);
+
+        let accounts: AccountsDB = [(7, acc)].into_iter().collect();
+
+        let config = config_from(&["csv-payments", "--profile", "bankx", "transactions.csv"]);
+        let mut out = Vec::new();
+
+        write_output(accounts, &config, &mut out).expect("output should be written");
+
+        assert_eq!(
+            String::from_utf8(out).expect("output should be valid UTF-8"),
+            "frozen,balance_total,balance_held,balance_available,customer_id\nfalse,2,0.5,1.5,7\n"
+        );
+    }
+
+    #[test]
+    fn write_pending_funds_only_with_profile() {
+        let accounts = || {
+            let mut acc = Account::new(3);
+            acc.funds_pending = Decimal::new(25, 1);
+
+            [(3, acc)].into_iter().collect::<AccountsDB>()
+        };
+
+        let mut out = Vec::new();
+
+        write_output(
+            accounts(),
+            &config_from(&["csv-payments", "transactions.csv"]),
+            &mut out,
+        )
+        .expect("output should be written");
+
+        assert_eq!(
+            String::from_utf8(out).expect("output should be valid UTF-8"),
+            "client,available,held,total,locked\n3,0,0,0,false\n"
+        );
+
+        let config = config_from(&["csv-payments", "--profile", "pending", "transactions.csv"]);
+        let mut out = Vec::new();
+
+        write_output(accounts(), &config, &mut out).expect("output should be written");
+
+        assert_eq!(
+            String::from_utf8(out).expect("output should be valid UTF-8"),
+            "client,available,held,pending,total,locked\n3,0,0,2.5,0,false\n"
+        );
+    }
+
+    #[test]
+    fn process_shuffled_transactions_in_order() {
+        let txs = VecDeque::<Transaction>::from([
+            Transaction {
+                r#type: TransactionType::Withdrawal,
+                client_id: 1,
+                tx_id: 3,
+                amount: Some(Decimal::new(4, 0)),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Dispute,
+                client_id: 1,
+                tx_id: 2,
+                amount: None,
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id: 2,
+                amount: Some(Decimal::new(3, 0)),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id: 1,
+                amount: Some(Decimal::new(5, 0)),
+                timestamp: None,
+            },
+        ]);
+
+        let priority = |tx: &Transaction| match tx.r#type {
+            TransactionType::Deposit => 0,
+            TransactionType::Withdrawal => 1,
+            _ => 2,
+        };
+
+        let ledger = process_transactions_ordered(txs, |a, b| priority(a).cmp(&priority(b)));
+
+        let client1 = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in finalized accounts");
+
+        // Both deposits land before the withdrawal, which would otherwise be rejected
+        assert_eq!(client1.funds_available, Decimal::new(1, 0));
+        assert_eq!(client1.funds_held, Decimal::new(3, 0));
+        assert_eq!(client1.funds_total, Decimal::new(4, 0));
+        assert!(ledger.errors.is_empty());
+    }
+
+    #[test]
+    fn read_numeric_type_codes() {
+        let input =
+            "type, client, tx, amount\n1, 1, 1, 5.0\n2, 1, 2, 1.0\n3, 1, 1,\nresolve, 1, 1,\n";
+
+        let (txs, _) = read(
+            input,
+            &[
+                "csv-payments",
+                "--type-codes",
+                "1=deposit,2=withdrawal,3=dispute",
+                "transactions.csv",
+            ],
+        );
+
+        let types: Vec<TransactionType> = txs.iter().map(|tx| tx.r#type).collect();
+        assert_eq!(
+            types,
+            [
+                TransactionType::Deposit,
+                TransactionType::Withdrawal,
+                TransactionType::Dispute,
+                TransactionType::Resolve,
+            ]
+        );
+
+        // Codes are rejected when they haven't been configured
+        let config = config_from(&["csv-payments", "transactions.csv"]);
+        let mut warnings = Vec::new();
+
+        let err = process_reader(input.as_bytes(), &config, &mut warnings)
+            .expect_err("unconfigured type codes should be rejected");
+
+        assert_eq!(
+            *err.downcast::<PaymentError>()
+                .expect("error should be a PaymentError"),
+            PaymentError::UnknownTypeCode { tx_id: 1, code: 1 }
+        );
+    }
+
+    #[test]
+    fn read_numeric_type_codes_from_json() {
+        let json = r#"[
+            {"type": 1, "client": 1, "tx": 1, "amount": "5.0"},
+            {"type": "2", "client": 1, "tx": 2, "amount": "1.0"},
+            {"type": "dispute", "client": 1, "tx": 1}
+        ]"#;
+
+        let (txs, _) = read(
+            json,
+            &[
+                "csv-payments",
+                "--input-format",
+                "json",
+                "--type-codes",
+                "1=deposit,2=withdrawal",
+                "transactions.json",
+            ],
+        );
+
+        let types: Vec<TransactionType> = txs.iter().map(|tx| tx.r#type).collect();
+        assert_eq!(
+            types,
+            [
+                TransactionType::Deposit,
+                TransactionType::Withdrawal,
+                TransactionType::Dispute,
+            ]
+        );
+    }
+
+    #[test]
+    fn quarantine_negative_accounts() {
+        let txs = VecDeque::<Transaction>::from([
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id: 1,
+                amount: Some(Decimal::new(10, 0)),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Withdrawal,
+                client_id: 1,
+                tx_id: 2,
+                amount: Some(Decimal::new(8, 0)),
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Dispute,
+                client_id: 1,
+                tx_id: 1,
+                amount: None,
+                timestamp: None,
+            },
+            Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 2,
+                tx_id: 3,
+                amount: Some(Decimal::new(5, 0)),
+                timestamp: None,
+            },
+        ]);
+
+        let ledger = process_transactions(txs);
+        let (clean, quarantined) = split_quarantined(ledger.accounts);
+
+        let config = config_from(&["csv-payments", "transactions.csv"]);
+
+        let mut clean_out = Vec::new();
+        write_output(clean, &config, &mut clean_out).expect("clean accounts should be written");
+
+        let mut quarantine_out = Vec::new();
+        write_output(quarantined, &config, &mut quarantine_out)
+            .expect("quarantined accounts should be written");
+
+        assert_eq!(
+            String::from_utf8(clean_out).expect("output should be valid UTF-8"),
+            "client,available,held,total,locked\n2,5,0,5,false\n"
+        );
+        assert_eq!(
+            String::from_utf8(quarantine_out).expect("output should be valid UTF-8"),
+            "client,available,held,total,locked\n1,-8,10,2,false\n"
+        );
+    }
+
+    #[test]
+    fn evict_settled_transactions() {
+        let txs = VecDeque::from([
+            tx(TransactionType::Deposit, 1, 1, Decimal::new(10, 0)),
+            tx(TransactionType::Deposit, 1, 2, Decimal::new(10, 0)),
+            tx(TransactionType::Dispute, 1, 1, None),
+            tx(TransactionType::Resolve, 1, 1, None),
+            tx(TransactionType::Dispute, 1, 1, None),
+        ]);
+
+        let ledger = process_ledger(
+            Ledger::with_policy(SettlementPolicy {
+                evict_settled: true,
+                ..Default::default()
+            }),
+            txs,
+        );
+
+        assert!(!ledger.ref_txs.contains_key(&1));
+        assert!(ledger.ref_txs.contains_key(&2));
+        assert_eq!(ledger.errors, [PaymentError::UnknownReference { tx_id: 1 }]);
+    }
+
+    #[test]
+    fn read_versioned_input() {
+        let v1 = "type, client, tx, amount\ndeposit, 1, 1, 2.5\nwithdrawal, 1, 2, 1.0\n";
+        let v2 = "type, client, tx, amount, currency\ndeposit, 1, 1, 2.5, USD\nwithdrawal, 1, 2, 1.0, USD\n";
+
+        let mut warnings = Vec::new();
+
+        let v1_txs = process_reader(
+            v1.as_bytes(),
+            &config_from(&["csv-payments", "transactions.csv"]),
+            &mut warnings,
+        )
+        .expect("v1 input should be read");
+
+        let v2_config = config_from(&["csv-payments", "--input-version", "2", "transactions.csv"]);
+
+        let v2_txs = process_reader(v2.as_bytes(), &v2_config, &mut warnings)
+            .expect("v2 input should be read");
+
+        let fields = |txs: VecDeque<Transaction<Balance>>| -> Vec<_> {
+            txs.iter()
+                .map(|tx| {
+                    (
+                        tx.r#type,
+                        tx.client_id,
+                        tx.tx_id,
+                        tx.amount.map(Amount::to_decimal),
+                    )
+                })
+                .collect()
+        };
+
+        assert_eq!(v1_txs.len(), 2);
+        assert_eq!(fields(v1_txs), fields(v2_txs));
+
+        let err = process_reader(v1.as_bytes(), &v2_config, &mut warnings)
+            .expect_err("v1 input should lack the v2 columns");
+
+        assert_eq!(
+            err.to_string(),
+            PaymentError::MissingColumn { column: "currency" }.to_string()
+        );
+    }
+
+    #[test]
+    fn process_readers_concurrently() {
+        let first = "type, client, tx, amount\ndeposit, 1, 1, 5.25\nwithdrawal, 1, 2, 2.5\n";
+        let second = "type, client, tx, amount\ndeposit, 2, 3, 2.5\ndispute, 2, 3,\n";
+
+        let config = config_from(&["csv-payments", "transactions.csv"]);
+        let mut warnings = Vec::new();
+
+        let accounts = process_readers(
+            vec![first.as_bytes(), second.as_bytes()],
+            &config,
+            &mut warnings,
+        )
+        .expect("both readers should be processed");
+
+        assert!(warnings.is_empty());
+        assert_eq!(accounts.len(), 2);
+
+        let acc = accounts
+            .get(&1)
+            .expect("Client 1 should exist in merged accounts");
+
+        assert_eq!(acc.funds_available.to_decimal(), Decimal::new(275, 2));
+        assert_eq!(acc.funds_total.to_decimal(), Decimal::new(275, 2));
+
+        let acc = accounts
+            .get(&2)
+            .expect("Client 2 should exist in merged accounts");
+
+        assert_eq!(acc.funds_available.to_decimal(), Decimal::new(0, 0));
+        assert_eq!(acc.funds_held.to_decimal(), Decimal::new(25, 1));
+    }
+
+    #[test]
+    fn report_transactions_in_several_readers() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 10\n";
+
+        let config = config_from(&["csv-payments", "transactions.csv"]);
+        let mut warnings = Vec::new();
+
+        let accounts = process_readers(
+            vec![input.as_bytes(), input.as_bytes()],
+            &config,
+            &mut warnings,
+        )
+        .expect("both readers should be processed");
+
+        // Each reader applied its copy, so both are in the balance
+        assert_eq!(warnings, [PaymentError::MergedDuplicate { tx_id: 1 }]);
+        assert_eq!(accounts[&1].funds_total.to_decimal(), Decimal::new(20, 0));
+    }
+
+    #[test]
+    fn report_rounding_adjustment() {
+        let mut accounts =
+            accounts_from_transactions([(1, 1005, 3), (2, 2555, 3), (3, 31249, 4)].map(
+                |(client_id, amount, scale)| Transaction {
+                    r#type: TransactionType::Deposit,
+                    client_id,
+                    tx_id: client_id.into(),
+                    amount: Some(Decimal::new(amount, scale)),
+                    timestamp: None,
+                },
+            ));
+
+        // 1.005 and 3.1249 round down by 0.005 and 0.0049, 2.555 rounds up by 0.005
+        assert_eq!(
+            round_accounts(&mut accounts, 2),
+            RoundingAdjustment {
+                available: Decimal::new(-49, 4),
+                total: Decimal::new(-49, 4),
+                ..Default::default()
+            }
+        );
+
+        let acc = accounts
+            .get(&2)
+            .expect("Client 2 should exist in rounded accounts");
+
+        assert_eq!(acc.funds_available, Decimal::new(256, 2));
+        assert_eq!(acc.funds_total, Decimal::new(256, 2));
+    }
+
+    #[test]
+    fn keep_rounded_total_consistent() {
+        let mut acc = Account::new(1);
+        acc.funds_available = Decimal::new(1005, 3);
+        acc.funds_held = Decimal::new(1005, 3);
+        acc.funds_total = Decimal::new(201, 2);
+
+        let mut accounts = AccountsDB::from([(1, acc)]);
+
+        // Both halves round down to 1.00, so the total has to drop to 2.00 with them
+        assert_eq!(
+            round_accounts(&mut accounts, 2),
+            RoundingAdjustment {
+                available: Decimal::new(-5, 3),
+                held: Decimal::new(-5, 3),
+                pending: Decimal::ZERO,
+                total: Decimal::new(-1, 2),
+            }
+        );
+
+        let acc = &accounts[&1];
+
+        assert_eq!(acc.funds_available, Decimal::new(100, 2));
+        assert_eq!(acc.funds_held, Decimal::new(100, 2));
+        assert_eq!(acc.funds_total, Decimal::new(200, 2));
+    }
+
+    #[test]
+    fn mmap_and_buffered_reads_agree() {
+        let path = "data/transactions_complex.csv";
+        let mut warnings = Vec::new();
+
+        let mut read = |args: &[&str]| -> Vec<_> {
+            process_csv(&config_from(args), &mut warnings)
+                .expect("input should be read")
+                .iter()
+                .map(|tx| {
+                    (
+                        tx.r#type,
+                        tx.client_id,
+                        tx.tx_id,
+                        tx.amount.map(Amount::to_decimal),
+                    )
+                })
+                .collect()
+        };
+
+        let buffered = read(&["csv-payments", path]);
+        let mapped = read(&["csv-payments", "--mmap", path]);
+
+        assert!(!buffered.is_empty());
+        assert_eq!(buffered, mapped);
+    }
+
+    #[test]
+    fn tolerate_broken_pipe_output() {
+        struct FullDisk;
+
+        impl io::Write for FullDisk {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("no space left on device"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let accounts = || {
+            accounts_from_transactions([Transaction {
+                r#type: TransactionType::Deposit,
+                client_id: 1,
+                tx_id: 1,
+                amount: Some(Decimal::new(25, 1)),
+                timestamp: None,
+            }])
+        };
+
+        let config = config_from(&["csv-payments", "transactions.csv"]);
+
+        assert!(write_output(accounts(), &config, &mut ClosedPipe).is_err());
+
+        let config = config_from(&["csv-payments", "--tolerate-broken-pipe", "transactions.csv"]);
+
+        assert!(write_output(accounts(), &config, &mut ClosedPipe).is_ok());
+        assert!(write_output(accounts(), &config, &mut FullDisk).is_err());
+    }
+
+    #[test]
+    fn drop_accounts_without_successful_activity() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 2.5\n\
+                     withdrawal, 1, 2, 1.0\n\
+                     withdrawal, 2, 3, 1.0\n\
+                     withdrawal, 2, 4, 5.0\n\
+                     authorize, 3, 5, 10.0\n\
+                     capture, 3, 5,\n";
+
+        let mut ledger = run(
+            input,
+            &["csv-payments", "--require-activity", "transactions.csv"],
+        );
+
+        assert_eq!(ledger.errors.len(), 2);
+        assert!(
+            !ledger
+                .accounts
+                .get(&2)
+                .expect("Client 2 should exist in ledger")
+                .had_successful_activity
+        );
+
+        retain_active(&mut ledger.accounts);
+
+        // A captured authorization counts as a deposit
+        assert_eq!(ledger.accounts.len(), 2);
+        assert!(ledger.accounts.contains_key(&1));
+        assert!(ledger.accounts.contains_key(&3));
+    }
+
+    #[test]
+    fn read_json_input() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 5.25\n\
+                   deposit, 2, 2, 2.5\n\
+                   withdrawal, 1, 3, 2.5\n\
+                   dispute, 2, 2,\n";
+        let json = r#"[
+            {"type": "deposit", "client": 1, "tx": 1, "amount": "5.25"},
+            {"type": "deposit", "client": 2, "tx": 2, "amount": 2.5},
+            {"type": "withdrawal", "client": 1, "tx": 3, "amount": "2.5"},
+            {"type": "dispute", "client": 2, "tx": 2}
+        ]"#;
+
+        let mut warnings = Vec::new();
+
+        let csv_txs = process_reader(
+            csv.as_bytes(),
+            &config_from(&["csv-payments", "transactions.csv"]),
+            &mut warnings,
+        )
+        .expect("CSV input should be read");
+
+        let json_txs = process_reader(
+            json.as_bytes(),
+            &config_from(&[
+                "csv-payments",
+                "--input-format",
+                "json",
+                "transactions.json",
+            ]),
+            &mut warnings,
+        )
+        .expect("JSON input should be read");
+
+        let balances = |txs: VecDeque<Transaction<Balance>>| -> Vec<_> {
+            process_transactions(txs)
+                .iter_sorted()
+                .map(|(client_id, acc)| {
+                    (
+                        *client_id,
+                        acc.funds_available.to_decimal(),
+                        acc.funds_held.to_decimal(),
+                        acc.funds_total.to_decimal(),
+                    )
+                })
+                .collect()
+        };
+
+        assert_eq!(balances(json_txs), balances(csv_txs));
+    }
+
+    #[test]
+    fn ignore_transactions_after_cutoff() {
+        let input = "type, client, tx, amount, timestamp\n\
+                     deposit, 1, 1, 5.25, 100\n\
+                     deposit, 1, 2, 2.5,\n\
+                     withdrawal, 1, 3, 2.5, 200\n\
+                     dispute, 1, 1, , 300\n";
+
+        let config = config_from(&["csv-payments", "--as-of", "150", "transactions.csv"]);
+        let mut warnings = Vec::new();
+
+        let txs =
+            process_reader(input.as_bytes(), &config, &mut warnings).expect("input should be read");
+        let (past, future) = split_as_of(txs, config.as_of.expect("cutoff should be set"));
+
+        assert_eq!(future.iter().map(|tx| tx.tx_id).collect::<Vec<_>>(), [3, 1]);
+
+        let ledger = process_transactions(past);
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available.to_decimal(), Decimal::new(775, 2));
+        assert_eq!(acc.funds_held.to_decimal(), Decimal::new(0, 0));
+    }
+
+    #[test]
+    fn reject_malformed_timestamps() {
+        let input = "type, client, tx, amount, timestamp\n\
+                     deposit, 1, 1, 5.25, 100\n\
+                     deposit, 1, 2, 7.0, 2030-01-01\n";
+
+        let config = config_from(&["csv-payments", "--as-of", "150", "transactions.csv"]);
+        let mut warnings = Vec::new();
+
+        // Reading it as undated would settle it despite the cutoff
+        let e = process_reader(input.as_bytes(), &config, &mut warnings)
+            .expect_err("malformed timestamp should be rejected");
+
+        assert!(e.to_string().contains("invalid digit"));
+    }
+
+    #[test]
+    fn minimize_transactions_for_client() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 5.25\n\
+                     deposit, 2, 2, 2.5\n\
+                     deposit, 3, 3, 1.0\n\
+                     dispute, 1, 2,\n\
+                     withdrawal, 2, 4, 1.0\n\
+                     deposit, 3, 5, 4.0\n\
+                     deposit, 1, 5, 2.5\n\
+                     chargeback, 1, 2,\n";
+
+        let (txs, _) = read(input, &["csv-payments", "transactions.csv"]);
+
+        let minimized = minimize(txs.clone(), 1);
+
+        // Client 2's disputed deposit and client 3's deposit reusing tx 5 are kept
+        assert_eq!(
+            minimized
+                .iter()
+                .map(|tx| (tx.client_id, tx.tx_id))
+                .collect::<Vec<_>>(),
+            [(1, 1), (2, 2), (1, 2), (3, 5), (1, 5), (1, 2)]
+        );
+
+        let balances = |ledger: Ledger<Balance>| {
+            let acc = ledger
+                .accounts
+                .get(&1)
+                .expect("Client 1 should exist in ledger");
+
+            (
+                acc.funds_available.to_decimal(),
+                acc.funds_held.to_decimal(),
+                acc.funds_total.to_decimal(),
+                acc.locked,
+            )
+        };
+
+        assert_eq!(
+            balances(process_transactions(minimized.into())),
+            balances(process_transactions(txs))
+        );
+    }
+
+    #[test]
+    fn load_config_file_overridden_by_flags() {
+        let path = env::temp_dir().join("csv-payments-config.toml");
+        fs::write(
+            &path,
+            "transactions = \"from-file.csv\"\n\
+             flush-every = 5\n\
+             empty-client = \"skip\"\n\
+             max-dispute-cycles = 2\n\
+             mmap = true\n\
+             fee = [\"deposit=0.10\", \"withdrawal=0.25\"]\n",
+        )
+        .expect("config file should be written");
+
+        let path = path.to_string_lossy().to_string();
+        let config = config_from(&["csv-payments", "--config", &path, "--flush-every", "10"]);
+
+        assert_eq!(config.transactions_path, "from-file.csv");
+        assert_eq!(config.flush_every, Some(10));
+        assert_eq!(config.empty_client, EmptyClientPolicy::Skip);
+        assert_eq!(config.policy.max_dispute_cycles, Some(2));
+        assert!(config.mmap);
+        assert_eq!(config.policy.fees.len(), 2);
+
+        let config = config_from(&["csv-payments", "--config", &path, "transactions.csv"]);
+
+        assert_eq!(config.transactions_path, "transactions.csv");
+        assert_eq!(config.flush_every, Some(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown option --flush_every.")]
+    fn reject_unknown_config_file_keys() {
+        let path = env::temp_dir().join("csv-payments-unknown-key.toml");
+        fs::write(&path, "flush_every = 2\n").expect("config file should be written");
+
+        let path = path.to_string_lossy().to_string();
+        config_from(&["csv-payments", "--config", &path, "transactions.csv"]);
+    }
+
+    #[test]
+    fn dispute_imported_transactions() {
+        let state = "client,available,held,total,locked\n1,10,0,10,false\n";
+        let refs = "type,client,tx,amount\ndeposit,1,1,4\n";
+        let input = "type, client, tx, amount\ndispute, 1, 1,\ndispute, 1, 9,\n";
+
+        let mut ledger = Ledger::new();
+
+        ledger.import_accounts(read_accounts(state.as_bytes()).expect("state should be read"));
+        ledger.import_refs(read_refs(refs.as_bytes()).expect("refs should be read"));
+
+        let (txs, _) = read(input, &["csv-payments", "transactions.csv"]);
+        let ledger = process_ledger(ledger, txs);
+
+        // tx 9 was neither imported nor in the batch
+        assert_eq!(ledger.errors, [PaymentError::UnknownReference { tx_id: 9 }]);
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available.to_decimal(), Decimal::new(6, 0));
+        assert_eq!(acc.funds_held.to_decimal(), Decimal::new(4, 0));
+        assert_eq!(acc.funds_total.to_decimal(), Decimal::new(10, 0));
+    }
+
+    #[test]
+    fn resolve_dispute_opened_in_imported_run() {
+        let first = "type, client, tx, amount\n\
+                     deposit, 1, 5, 10\n\
+                     dispute, 1, 5,\n\
+                     deposit, 2, 6, 3\n";
+        let second = "type, client, tx, amount\ndispute, 1, 5,\nresolve, 1, 5,\n";
+
+        let args = ["csv-payments", "transactions.csv"];
+        let ledger = run(first, &args);
+
+        let mut refs = Vec::new();
+        write_refs(&ledger.ref_txs, &mut refs).expect("refs should be written");
+
+        let mut state = Vec::new();
+        write_output(ledger.accounts, &config_from(&args), &mut state)
+            .expect("output should be written");
+
+        let mut ledger = Ledger::new();
+
+        ledger.import_accounts(read_accounts(&state[..]).expect("state should be read"));
+        ledger.import_refs(read_refs(&refs[..]).expect("refs should be read"));
+
+        let (txs, _) = read(second, &args);
+        let mut ledger = process_ledger(ledger, txs);
+
+        // The dispute is still open from the first run, so it can't hold the deposit again
+        assert_eq!(ledger.errors, [PaymentError::ExcessiveDispute { tx_id: 5 }]);
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available.to_decimal(), Decimal::new(10, 0));
+        assert_eq!(acc.funds_held.to_decimal(), Decimal::new(0, 0));
+        assert_eq!(acc.funds_total.to_decimal(), Decimal::new(10, 0));
+
+        // Imported accounts were active in the earlier run
+        retain_active(&mut ledger.accounts);
+
+        assert_eq!(ledger.accounts.len(), 2);
+    }
+
+    #[test]
+    fn capture_authorization_opened_in_imported_run() {
+        let first = "type, client, tx, amount\ndeposit, 1, 1, 1\nauthorize, 1, 2, 10\n";
+        let second = "type, client, tx, amount\ncapture, 1, 2,\n";
+
+        let args = ["csv-payments", "transactions.csv"];
+        let pending_config =
+            config_from(&["csv-payments", "--profile", "pending", "transactions.csv"]);
+        let ledger = run(first, &args);
+
+        let mut refs = Vec::new();
+        write_refs(&ledger.ref_txs, &mut refs).expect("refs should be written");
+
+        let mut state = Vec::new();
+        write_output(ledger.accounts, &pending_config, &mut state)
+            .expect("output should be written");
+
+        let mut ledger = Ledger::new();
+
+        ledger.import_accounts(read_accounts(&state[..]).expect("state should be read"));
+        ledger.import_refs(read_refs(&refs[..]).expect("refs should be read"));
+
+        let (txs, _) = read(second, &args);
+        let ledger = process_ledger(ledger, txs);
+
+        assert!(ledger.errors.is_empty());
+
+        let acc = ledger
+            .accounts
+            .get(&1)
+            .expect("Client 1 should exist in ledger");
+
+        assert_eq!(acc.funds_available.to_decimal(), Decimal::new(11, 0));
+        assert_eq!(acc.funds_pending.to_decimal(), Decimal::new(0, 0));
+        assert_eq!(acc.funds_total.to_decimal(), Decimal::new(11, 0));
+
+        // Without the pending column the authorization can't be captured
+        let state = "client,available,held,total,locked\n1,1,0,1,false\n";
+        let mut ledger = Ledger::new();
+
+        ledger.import_accounts(read_accounts(state.as_bytes()).expect("state should be read"));
+        ledger.import_refs(read_refs(&refs[..]).expect("refs should be read"));
+
+        let (txs, _) = read(second, &args);
+        let ledger = process_ledger(ledger, txs);
+
+        assert_eq!(
+            ledger.errors,
+            [
+                PaymentError::UncoveredAuthorization { tx_id: 2 },
+                PaymentError::UnknownReference { tx_id: 2 },
+            ]
+        );
+        assert_eq!(
+            ledger.accounts[&1].funds_pending.to_decimal(),
+            Decimal::new(0, 0)
+        );
+    }
+
+    #[test]
+    fn hash_identical_results_identically() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 5.25\n\
+                     deposit, 2, 2, 2.50\n\
+                     deposit, 3, 3, 1.0\n\
+                     dispute, 2, 2,\n";
+
+        let hash =
+            |input: &str| result_hash(&run(input, &["csv-payments", "transactions.csv"]).accounts);
+
+        assert_eq!(hash(input), hash(input));
+        assert_eq!(hash(input), hash(&input.replace("2.50", "2.5")));
+        assert_ne!(hash(input), hash(&input.replace("5.25", "5.26")));
+    }
+}
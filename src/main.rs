@@ -1,241 +1,290 @@
-mod account;
-mod config;
-mod transaction;
-
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 use std::env;
 use std::error::Error;
+use std::fs::{self, File};
 use std::io;
+use std::path::Path;
 use std::process;
 
-use config::Config;
-use csv::{ReaderBuilder, Trim, WriterBuilder};
+use csv_payments::amount::Balance;
+use csv_payments::bundle::write_bundle;
+use csv_payments::config::{Config, OutputFormat};
+use csv_payments::latency::Latencies;
+use csv_payments::ledger::Ledger;
+#[cfg(feature = "parquet")]
+use csv_payments::parquet_output::write_parquet;
+use csv_payments::series::{write_balance_series, BalancePoint};
+use csv_payments::state::{read_state, write_state};
+use csv_payments::transaction::{Transaction, TransactionType};
+use csv_payments::{
+    check_open_disputes, process_csv, process_with_checkpoints, read_accounts, read_refs,
+    result_hash, retain_active, round_accounts, split_as_of, split_quarantined, write_output,
+    write_refs,
+};
+
+/// Loads accounts and reference transactions from an earlier run into `ledger`
+fn import_state(ledger: &mut Ledger<Balance>, config: &Config) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = &config.import_state_path {
+        ledger.import_accounts(read_accounts(File::open(path)?)?);
+    }
 
-use crate::account::Account;
-use crate::transaction::{Transaction, TransactionType};
+    if let Some(path) = &config.import_refs_path {
+        ledger.import_refs(read_refs(File::open(path)?)?);
+    }
 
-type AccountsDB = HashMap<u16, Account>;
-type TransactionsDB = HashMap<u32, Transaction>;
+    Ok(())
+}
+
+/// Writes the full state through a temporary file, so an interrupted write leaves the previously
+/// saved state intact
+fn save_state(
+    path: &str,
+    ledger: &Ledger<Balance>,
+    position: usize,
+    requeued: &VecDeque<Transaction<Balance>>,
+) -> Result<(), Box<dyn Error>> {
+    let partial = format!("{path}.partial");
+
+    write_state(
+        &ledger.save_state(position, requeued),
+        &mut File::create(&partial)?,
+    )?;
+    fs::rename(&partial, path)?;
+
+    Ok(())
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let config = Config::new(&args);
 
-    match process_csv(&config) {
+    let mut warnings = Vec::new();
+
+    match process_csv(&config, &mut warnings) {
         Ok(txs) => {
-            let finalized_accounts = process_transactions(txs);
+            for e in &warnings {
+                eprintln!("Warning: {e}");
+            }
 
-            if let Err(e) = write_output(finalized_accounts) {
-                eprintln!("CSV output error: {e}");
+            let txs = match config.as_of {
+                Some(cutoff) => {
+                    let (txs, future) = split_as_of(txs, cutoff);
+
+                    if !future.is_empty() {
+                        eprintln!("Ignored {} transactions dated after {cutoff}", future.len());
+                    }
+
+                    txs
+                }
+                None => txs,
+            };
+
+            let (mut ledger, skipped, mut txs, requeued) = match &config.resume_path {
+                Some(path) => match File::open(path).map_err(|e| e.into()).and_then(read_state) {
+                    Ok(state) => {
+                        let (ledger, position, requeued) =
+                            Ledger::resume(state, config.policy.clone());
+
+                        if position > txs.len() {
+                            eprintln!(
+                                "Resume error: the state was saved after {position} transactions, \
+                                 but the input only has {}",
+                                txs.len()
+                            );
+
+                            process::exit(1);
+                        }
+
+                        (ledger, position, txs, requeued)
+                    }
+                    Err(e) => {
+                        eprintln!("Resume error: {e}");
+
+                        process::exit(1);
+                    }
+                },
+                None => (
+                    Ledger::with_policy(config.policy.clone()),
+                    0,
+                    txs,
+                    VecDeque::new(),
+                ),
+            };
+
+            // The resumed run has already settled these
+            txs.drain(..skipped);
+            let input_len = txs.len();
+
+            if config.profile_latency {
+                ledger.latencies = Some(Latencies::default());
+            }
+
+            if let Err(e) = import_state(&mut ledger, &config) {
+                eprintln!("Import error: {e}");
 
                 process::exit(1);
             }
-        }
-        Err(e) => {
-            eprintln!("CSV processing error: {e}");
 
-            process::exit(1);
-        }
-    }
-}
+            let series_client = config
+                .balance_series
+                .as_ref()
+                .map(|(client_id, _)| *client_id);
+            let mut series = Vec::new();
+
+            let mut ledger = process_with_checkpoints(
+                ledger,
+                txs,
+                requeued,
+                config.save_state_every,
+                |tx, acc| {
+                    if Some(tx.client_id) == series_client {
+                        series.push(BalancePoint::new(tx, acc));
+                    }
+                },
+                |_, _| {},
+                |ledger, consumed, requeued| {
+                    if let Some(path) = &config.save_state_path {
+                        if let Err(e) = save_state(path, ledger, skipped + consumed, requeued) {
+                            eprintln!("State error: {e}");
+
+                            process::exit(1);
+                        }
+                    }
+                },
+            );
+
+            if let Some(path) = &config.save_state_path {
+                if let Err(e) = save_state(path, &ledger, skipped + input_len, &VecDeque::new()) {
+                    eprintln!("State error: {e}");
+
+                    process::exit(1);
+                }
+            }
 
-fn process_csv(config: &Config) -> Result<VecDeque<Transaction>, Box<dyn Error>> {
-    let mut unprocessed_transactions = VecDeque::<Transaction>::new();
+            if let Some((_, path)) = &config.balance_series {
+                let written = File::create(path)
+                    .map_err(|e| e.into())
+                    .and_then(|mut file| write_balance_series(&series, &mut file));
 
-    let mut reader = ReaderBuilder::new()
-        .trim(Trim::All)
-        .from_path(config.transactions_path.clone())?;
+                if let Err(e) = written {
+                    eprintln!("Balance series error: {e}");
 
-    for result in reader.deserialize() {
-        let tx: Transaction = result?;
-        unprocessed_transactions.push_back(tx);
-    }
+                    process::exit(1);
+                }
+            }
 
-    Ok(unprocessed_transactions)
-}
+            for e in &ledger.errors {
+                eprintln!("Rejected transaction: {e}");
+            }
 
-// The account and reference transaction data stores are created inside this function for ease-of-use
-// In a real-world system, connections to these external data sources would be passed in via
-// parameters if needed
-fn process_transactions(mut unprocessed_transactions: VecDeque<Transaction>) -> AccountsDB {
-    let mut accounts = AccountsDB::new();
-    let mut ref_txs = TransactionsDB::new();
-
-    while !unprocessed_transactions.is_empty() {
-        let tx = unprocessed_transactions
-            .pop_front()
-            .expect("transaction should exist");
-
-        let acc = accounts
-            .entry(tx.client_id)
-            .or_insert_with(|| Account::new(tx.client_id));
-
-        if tx.r#type == TransactionType::Deposit || tx.r#type == TransactionType::Withdrawal {
-            acc.settle_transaction(&tx, None);
-            ref_txs.insert(tx.tx_id, tx);
-        } else if let Some(ref_tx) = ref_txs.get(&tx.tx_id) {
-            acc.settle_transaction(&tx, Some(ref_tx));
-        } else {
-            unprocessed_transactions.push_back(tx);
-        }
-    }
+            for e in &ledger.warnings {
+                eprintln!("Warning: {e}");
+            }
 
-    accounts
-}
+            if let Err(e) = check_open_disputes(&ledger, &config) {
+                eprintln!("Closeout error: {e}");
 
-fn write_output(accounts: AccountsDB) -> Result<(), Box<dyn Error>> {
-    let mut writer = WriterBuilder::new().from_writer(io::stdout());
+                process::exit(1);
+            }
 
-    for (_, acc) in accounts {
-        writer.serialize(acc)?;
-    }
+            if let Some(latencies) = &ledger.latencies {
+                eprint!("{}", latencies.report());
+            }
 
-    writer.flush()?;
-    Ok(())
-}
+            for (r#type, name) in [
+                (TransactionType::Deposit, "deposits"),
+                (TransactionType::Withdrawal, "withdrawals"),
+            ] {
+                if let Some(total) = ledger.fee_totals.get(&r#type) {
+                    eprintln!("Fees charged on {name}: {total}");
+                }
+            }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::VecDeque;
-
-    use rust_decimal::Decimal;
-
-    use crate::{
-        process_transactions,
-        transaction::{Transaction, TransactionType},
-    };
-
-    #[test]
-    fn process_basic_transactions() {
-        let deposit1 = Transaction {
-            r#type: TransactionType::Deposit,
-            client_id: 1,
-            tx_id: 1,
-            amount: Some(Decimal::new(10, 1)),
-        };
-
-        let deposit2 = Transaction {
-            r#type: TransactionType::Deposit,
-            client_id: 2,
-            tx_id: 2,
-            amount: Some(Decimal::new(20, 1)),
-        };
-
-        let deposit3 = Transaction {
-            r#type: TransactionType::Deposit,
-            client_id: 1,
-            tx_id: 3,
-            amount: Some(Decimal::new(20, 1)),
-        };
-
-        let withdrawal1 = Transaction {
-            r#type: TransactionType::Withdrawal,
-            client_id: 1,
-            tx_id: 4,
-            amount: Some(Decimal::new(15, 1)),
-        };
-
-        let withdrawal2 = Transaction {
-            r#type: TransactionType::Withdrawal,
-            client_id: 2,
-            tx_id: 5,
-            amount: Some(Decimal::new(30, 1)),
-        };
-
-        let unprocessed_transactions =
-            VecDeque::<Transaction>::from([deposit1, deposit2, deposit3, withdrawal1, withdrawal2]);
-
-        let finalized_accounts = process_transactions(unprocessed_transactions);
-
-        let client1 = finalized_accounts
-            .get(&1)
-            .expect("Client 1 should exist in finalized accounts");
-
-        assert_eq!(client1.funds_available, Decimal::new(15, 1));
-        assert_eq!(client1.funds_held, Decimal::new(0, 0));
-        assert_eq!(client1.funds_total, Decimal::new(15, 1));
-        assert_eq!(client1.locked, false);
-
-        let client2 = finalized_accounts
-            .get(&2)
-            .expect("Client 2 should exist in finalized accounts");
-
-        assert_eq!(client2.funds_available, Decimal::new(2, 0));
-        assert_eq!(client2.funds_held, Decimal::new(0, 0));
-        assert_eq!(client2.funds_total, Decimal::new(2, 0));
-        assert_eq!(client2.locked, false);
-    }
+            if config.verify_conservation {
+                let violations = ledger.verify_conservation();
+
+                for e in &violations {
+                    eprintln!("Conservation error: {e}");
+                }
+
+                if !violations.is_empty() {
+                    process::exit(1);
+                }
+            }
+
+            if let Some(path) = &config.dump_refs_path {
+                let dumped = File::create(path)
+                    .map_err(|e| e.into())
+                    .and_then(|mut file| write_refs(&ledger.ref_txs, &mut file));
+
+                if let Err(e) = dumped {
+                    eprintln!("Reference dump error: {e}");
+
+                    process::exit(1);
+                }
+            }
+
+            if let Some(dp) = config.round_output {
+                let adjustment = round_accounts(&mut ledger.accounts, dp);
+
+                if config.rounding_report {
+                    eprintln!("Rounding adjustment: {adjustment}");
+                }
+            }
+
+            if config.require_activity {
+                retain_active(&mut ledger.accounts);
+            }
+
+            if config.result_hash {
+                eprintln!("Result hash: {:016x}", result_hash(&ledger.accounts));
+            }
+
+            if let Some(dir) = &config.bundle_dir {
+                if let Err(e) = write_bundle(Path::new(dir), &config, &ledger, &warnings) {
+                    eprintln!("Bundle error: {e}");
 
-    #[test]
-    fn process_complex_transactions() {
-        let deposit1 = Transaction {
-            r#type: TransactionType::Deposit,
-            client_id: 1,
-            tx_id: 1,
-            amount: Some(Decimal::new(500_0005, 4)),
-        };
-
-        let deposit2 = Transaction {
-            r#type: TransactionType::Deposit,
-            client_id: 1,
-            tx_id: 2,
-            amount: Some(Decimal::new(1000, 0)),
-        };
-
-        let dispute1 = Transaction {
-            r#type: TransactionType::Dispute,
-            client_id: 1,
-            tx_id: 1,
-            amount: None,
-        };
-
-        let resolve1 = Transaction {
-            r#type: TransactionType::Resolve,
-            client_id: 1,
-            tx_id: 1,
-            amount: None,
-        };
-
-        let deposit3 = Transaction {
-            r#type: TransactionType::Deposit,
-            client_id: 1,
-            tx_id: 3,
-            amount: Some(Decimal::new(100, 0)),
-        };
-
-        let dispute2 = Transaction {
-            r#type: TransactionType::Dispute,
-            client_id: 1,
-            tx_id: 3,
-            amount: None,
-        };
-
-        let chargeback1 = Transaction {
-            r#type: TransactionType::Chargeback,
-            client_id: 1,
-            tx_id: 3,
-            amount: None,
-        };
-
-        let txs = VecDeque::<Transaction>::from([
-            deposit1,
-            deposit2,
-            dispute1,
-            resolve1,
-            deposit3,
-            dispute2,
-            chargeback1,
-        ]);
-
-        let finalized_accounts = process_transactions(txs);
-
-        let client1 = finalized_accounts
-            .get(&1)
-            .expect("Client 1 should exist in finalized accounts");
-
-        assert_eq!(client1.funds_available, Decimal::new(1500_0005, 4));
-        assert_eq!(client1.funds_held, Decimal::new(0, 0));
-        assert_eq!(client1.funds_total, Decimal::new(1500_0005, 4));
-        assert_eq!(client1.locked, true);
+                    process::exit(1);
+                }
+            }
+
+            let accounts = match &config.quarantine_path {
+                Some(path) => {
+                    let (clean, quarantined) = split_quarantined(ledger.accounts);
+
+                    let written = File::create(path)
+                        .map_err(|e| e.into())
+                        .and_then(|mut file| write_output(quarantined, &config, &mut file));
+
+                    if let Err(e) = written {
+                        eprintln!("Quarantine output error: {e}");
+
+                        process::exit(1);
+                    }
+
+                    clean
+                }
+                None => ledger.accounts,
+            };
+
+            let written = match &config.output_format {
+                OutputFormat::Csv => write_output(accounts, &config, &mut io::stdout()),
+                #[cfg(feature = "parquet")]
+                OutputFormat::Parquet(path) => File::create(path)
+                    .map_err(|e| e.into())
+                    .and_then(|file| write_parquet(&accounts, file)),
+            };
+
+            if let Err(e) = written {
+                eprintln!("Output error: {e}");
+
+                process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("CSV processing error: {e}");
+
+            process::exit(1);
+        }
     }
 }
@@ -0,0 +1,143 @@
+use std::error::Error;
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, BooleanArray, Decimal128Array, RecordBatch, UInt16Array};
+use arrow_schema::{DataType, Field, Schema, DECIMAL128_MAX_PRECISION};
+use parquet::arrow::ArrowWriter;
+
+use crate::account::Account;
+use crate::amount::Amount;
+use crate::ledger::AccountsDB;
+
+/// Writes the accounts as a Parquet file ordered by client id. Balances share a single decimal
+/// scale, the largest needed by any of them, so no value loses precision.
+pub fn write_parquet<A: Amount, W: Write + Send>(
+    accounts: &AccountsDB<A>,
+    out: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut accounts: Vec<&Account<A>> = accounts.values().collect();
+    accounts.sort_by_key(|acc| acc.client_id);
+
+    let balances: [fn(&Account<A>) -> A; 4] = [
+        |acc| acc.funds_available,
+        |acc| acc.funds_held,
+        |acc| acc.funds_pending,
+        |acc| acc.funds_total,
+    ];
+
+    let scale = accounts
+        .iter()
+        .flat_map(|acc| balances.iter().map(move |balance| balance(acc)))
+        .map(|value| value.to_decimal().scale())
+        .max()
+        .unwrap_or(0);
+
+    let decimal_type = DataType::Decimal128(DECIMAL128_MAX_PRECISION, scale as i8);
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", decimal_type.clone(), false),
+        Field::new("held", decimal_type.clone(), false),
+        Field::new("pending", decimal_type.clone(), false),
+        Field::new("total", decimal_type, false),
+        Field::new("locked", DataType::Boolean, false),
+    ]));
+
+    let mut columns: Vec<ArrayRef> = vec![Arc::new(UInt16Array::from_iter_values(
+        accounts.iter().map(|acc| acc.client_id),
+    ))];
+
+    for balance in balances {
+        let values = Decimal128Array::from_iter_values(accounts.iter().map(|acc| {
+            let mut value = balance(acc).to_decimal();
+            value.rescale(scale);
+            value.mantissa()
+        }))
+        .with_precision_and_scale(DECIMAL128_MAX_PRECISION, scale as i8)?;
+
+        columns.push(Arc::new(values));
+    }
+
+    columns.push(Arc::new(BooleanArray::from_iter(
+        accounts.iter().map(|acc| Some(acc.locked)),
+    )));
+
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let mut writer = ArrowWriter::try_new(out, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+
+    use arrow_array::{Array, BooleanArray, Decimal128Array, UInt16Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use rust_decimal::Decimal;
+
+    use crate::{account::Account, ledger::AccountsDB, parquet_output::write_parquet};
+
+    #[test]
+    fn write_and_read_parquet() {
+        let mut acc1 = Account::new(1);
+        acc1.funds_available = Decimal::new(15, 1);
+        acc1.funds_total = Decimal::new(15, 1);
+
+        let mut acc2 = Account::new(2);
+        acc2.funds_held = Decimal::new(2_0005, 4);
+        acc2.funds_total = Decimal::new(2_0005, 4);
+        acc2.locked = true;
+
+        let accounts: AccountsDB = [(2, acc2), (1, acc1)].into_iter().collect();
+
+        let path = env::temp_dir().join("csv-payments-accounts.parquet");
+        let file = File::create(&path).expect("output file should be created");
+
+        write_parquet(&accounts, file).expect("accounts should be written");
+
+        let file = File::open(&path).expect("output file should be readable");
+        let batch = ParquetRecordBatchReaderBuilder::try_new(file)
+            .expect("output should be valid Parquet")
+            .build()
+            .expect("output should be valid Parquet")
+            .next()
+            .expect("output should contain a batch")
+            .expect("batch should be readable");
+
+        let column = |name: &str| batch.column_by_name(name).expect("column should exist");
+
+        let clients = column("client")
+            .as_any()
+            .downcast_ref::<UInt16Array>()
+            .expect("client should be a u16 column");
+        assert_eq!(clients.values(), &[1, 2]);
+
+        let available = column("available")
+            .as_any()
+            .downcast_ref::<Decimal128Array>()
+            .expect("available should be a decimal column");
+        assert_eq!(available.scale(), 4);
+        assert_eq!(available.value(0), 1_5000);
+        assert_eq!(available.value(1), 0);
+
+        let held = column("held")
+            .as_any()
+            .downcast_ref::<Decimal128Array>()
+            .expect("held should be a decimal column");
+        assert_eq!(held.value(1), 2_0005);
+
+        let locked = column("locked")
+            .as_any()
+            .downcast_ref::<BooleanArray>()
+            .expect("locked should be a bool column");
+        assert!(!locked.value(0));
+        assert!(locked.value(1));
+        assert_eq!(locked.len(), 2);
+    }
+}
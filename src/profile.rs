@@ -0,0 +1,62 @@
+use crate::account::Account;
+use crate::amount::Amount;
+
+/// An account field which can be written to the output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Client,
+    Available,
+    Held,
+    Pending,
+    Total,
+    Locked,
+}
+
+impl Column {
+    pub fn value<A: Amount>(self, acc: &Account<A>) -> String {
+        match self {
+            Column::Client => acc.client_id.to_string(),
+            Column::Available => acc.funds_available.to_string(),
+            Column::Held => acc.funds_held.to_string(),
+            Column::Pending => acc.funds_pending.to_string(),
+            Column::Total => acc.funds_total.to_string(),
+            Column::Locked => acc.locked.to_string(),
+        }
+    }
+}
+
+/// A named preset of output columns, in order, along with the header each is written under
+#[derive(Debug, PartialEq)]
+pub struct OutputProfile {
+    pub name: &'static str,
+    pub columns: &'static [(Column, &'static str)],
+}
+
+/// Output layouts required by specific downstream consumers
+pub const PROFILES: &[OutputProfile] = &[
+    OutputProfile {
+        name: "bankx",
+        columns: &[
+            (Column::Locked, "frozen"),
+            (Column::Total, "balance_total"),
+            (Column::Held, "balance_held"),
+            (Column::Available, "balance_available"),
+            (Column::Client, "customer_id"),
+        ],
+    },
+    OutputProfile {
+        name: "pending",
+        columns: &[
+            (Column::Client, "client"),
+            (Column::Available, "available"),
+            (Column::Held, "held"),
+            (Column::Pending, "pending"),
+            (Column::Total, "total"),
+            (Column::Locked, "locked"),
+        ],
+    },
+];
+
+pub fn find_profile(name: &str) -> Option<&'static OutputProfile> {
+    PROFILES.iter().find(|profile| profile.name == name)
+}
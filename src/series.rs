@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::io;
+
+use csv::WriterBuilder;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::account::Account;
+use crate::amount::Amount;
+use crate::transaction::Transaction;
+
+/// A client's balances right after one of its transactions was applied
+#[derive(Debug, Serialize, PartialEq)]
+pub struct BalancePoint<A = Decimal> {
+    pub timestamp: Option<u64>,
+    pub available: A,
+    pub held: A,
+    pub total: A,
+}
+
+impl<A: Amount> BalancePoint<A> {
+    pub fn new(tx: &Transaction<A>, acc: &Account<A>) -> BalancePoint<A> {
+        BalancePoint {
+            timestamp: tx.timestamp,
+            available: acc.funds_available,
+            held: acc.funds_held,
+            total: acc.funds_total,
+        }
+    }
+}
+
+/// Writes the series as CSV, leaving the timestamp empty for transactions without one
+pub fn write_balance_series<A: Amount, W: io::Write>(
+    series: &[BalancePoint<A>],
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = WriterBuilder::new().from_writer(out);
+
+    for point in series {
+        writer.serialize(point)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ledger::Ledger,
+        process_with_steps,
+        series::{write_balance_series, BalancePoint},
+        tests::read,
+    };
+
+    #[test]
+    fn record_client_balance_series() {
+        let input = "type, client, tx, amount, timestamp\n\
+                     deposit, 1, 1, 5.25, 100\n\
+                     deposit, 2, 2, 2.5, 110\n\
+                     withdrawal, 1, 3, 2.5, 120\n\
+                     withdrawal, 1, 4, 10.0, 130\n\
+                     deposit, 1, 5, 2.5, 140\n";
+
+        let (txs, _) = read(input, &["csv-payments", "transactions.csv"]);
+
+        let mut series = Vec::new();
+
+        let ledger = process_with_steps(
+            Ledger::new(),
+            txs,
+            |tx, acc| {
+                if tx.client_id == 1 {
+                    series.push(BalancePoint::new(tx, acc))
+                }
+            },
+            |_, _| {},
+        );
+
+        // The second withdrawal is rejected, leaving three applied transactions for client 1
+        assert_eq!(ledger.errors.len(), 1);
+        assert_eq!(series.len(), 3);
+        assert_eq!(
+            series
+                .iter()
+                .map(|point| point.timestamp)
+                .collect::<Vec<_>>(),
+            [Some(100), Some(120), Some(140)]
+        );
+
+        let mut out = Vec::new();
+        write_balance_series(&series, &mut out).expect("series should be written");
+
+        let out = String::from_utf8(out).expect("output should be valid UTF-8");
+
+        assert_eq!(out.lines().next(), Some("timestamp,available,held,total"));
+        assert_eq!(out.lines().count(), 4);
+    }
+}
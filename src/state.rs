@@ -0,0 +1,258 @@
+use std::error::Error;
+use std::io;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::account::Account;
+use crate::amount::{Amount, Balance};
+use crate::transaction::{Transaction, TransactionRecord, TransactionState, TransactionType};
+
+/// Everything a ledger needs to carry on settling where it left off, including open disputes, how
+/// far into the input it got and the transactions which were requeued until the transactions
+/// they reference arrive
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EngineState<A = Decimal> {
+    /// In client order
+    pub accounts: Vec<SavedAccount<A>>,
+    /// In tx id order
+    pub ref_txs: Vec<SavedRecord<A>>,
+    pub evicted: Vec<u32>,
+    pub disputes_opened: Vec<(u16, u32)>,
+    pub transaction_sizes: Vec<(u16, Decimal, u32)>,
+    pub fee_totals: Vec<(TransactionType, Decimal)>,
+    /// Number of input transactions taken off the queue, which a resumed run skips
+    pub position: usize,
+    /// In queue order
+    pub requeued: Vec<SavedTransaction<A>>,
+}
+
+/// An account including the fields left out of the output format
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedAccount<A = Decimal> {
+    pub client_id: u16,
+    pub available: A,
+    pub held: A,
+    pub pending: A,
+    pub total: A,
+    pub locked: bool,
+    pub chargebacks: Vec<u32>,
+    pub had_successful_activity: bool,
+}
+
+/// A transaction including its timestamp, which the input CSV format leaves out when written
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedTransaction<A = Decimal> {
+    pub r#type: TransactionType,
+    pub client_id: u16,
+    pub tx_id: u32,
+    pub amount: Option<A>,
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedRecord<A = Decimal> {
+    pub tx: SavedTransaction<A>,
+    pub state: TransactionState,
+    pub dispute_cycles: u32,
+    pub disputed: A,
+    pub charged_back: A,
+}
+
+impl<A: Amount> From<&Account<A>> for SavedAccount<A> {
+    fn from(acc: &Account<A>) -> Self {
+        let mut chargebacks: Vec<u32> = acc.chargebacks.iter().copied().collect();
+        chargebacks.sort_unstable();
+
+        SavedAccount {
+            client_id: acc.client_id,
+            available: acc.funds_available,
+            held: acc.funds_held,
+            pending: acc.funds_pending,
+            total: acc.funds_total,
+            locked: acc.locked,
+            chargebacks,
+            had_successful_activity: acc.had_successful_activity,
+        }
+    }
+}
+
+impl<A: Amount> From<SavedAccount<A>> for Account<A> {
+    fn from(saved: SavedAccount<A>) -> Self {
+        Account {
+            client_id: saved.client_id,
+            funds_available: saved.available,
+            funds_held: saved.held,
+            funds_pending: saved.pending,
+            funds_total: saved.total,
+            locked: saved.locked,
+            chargebacks: saved.chargebacks.into_iter().collect(),
+            had_successful_activity: saved.had_successful_activity,
+        }
+    }
+}
+
+impl<A: Amount> From<&Transaction<A>> for SavedTransaction<A> {
+    fn from(tx: &Transaction<A>) -> Self {
+        SavedTransaction {
+            r#type: tx.r#type,
+            client_id: tx.client_id,
+            tx_id: tx.tx_id,
+            amount: tx.amount,
+            timestamp: tx.timestamp,
+        }
+    }
+}
+
+impl<A: Amount> From<SavedTransaction<A>> for Transaction<A> {
+    fn from(saved: SavedTransaction<A>) -> Self {
+        Transaction {
+            r#type: saved.r#type,
+            client_id: saved.client_id,
+            tx_id: saved.tx_id,
+            amount: saved.amount,
+            timestamp: saved.timestamp,
+        }
+    }
+}
+
+impl<A: Amount> From<&TransactionRecord<A>> for SavedRecord<A> {
+    fn from(record: &TransactionRecord<A>) -> Self {
+        SavedRecord {
+            tx: SavedTransaction::from(&record.tx),
+            state: record.state,
+            dispute_cycles: record.dispute_cycles,
+            disputed: record.disputed,
+            charged_back: record.charged_back,
+        }
+    }
+}
+
+impl<A: Amount> From<SavedRecord<A>> for TransactionRecord<A> {
+    fn from(saved: SavedRecord<A>) -> Self {
+        TransactionRecord {
+            tx: saved.tx.into(),
+            state: saved.state,
+            dispute_cycles: saved.dispute_cycles,
+            disputed: saved.disputed,
+            charged_back: saved.charged_back,
+        }
+    }
+}
+
+/// Writes the state as JSON
+pub fn write_state<A: Amount, W: io::Write>(
+    state: &EngineState<A>,
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    serde_json::to_writer(&mut *out, state)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Reads a state written by `write_state`
+pub fn read_state<R: io::Read>(input: R) -> Result<EngineState<Balance>, Box<dyn Error>> {
+    Ok(serde_json::from_reader(io::BufReader::new(input))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use crate::{
+        amount::{Amount, Balance},
+        ledger::Ledger,
+        process_ledger, process_with_checkpoints,
+        state::{read_state, write_state},
+        tests::read,
+    };
+
+    #[test]
+    fn resume_from_saved_state() {
+        // The dispute of tx 4 arrives before tx 4 itself, so it's still requeued when the state
+        // is saved
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 10.0\n\
+                     deposit, 2, 2, 5.0\n\
+                     dispute, 2, 4,\n\
+                     dispute, 1, 1, 4.0\n\
+                     deposit, 2, 4, 3.0\n\
+                     withdrawal, 1, 5, 2.0\n\
+                     resolve, 1, 1,\n\
+                     dispute, 1, 1,\n\
+                     chargeback, 1, 1,\n\
+                     deposit, 2, 6, 1.5\n\
+                     deposit, 2, 2, 1.0\n";
+
+        let (txs, _) = read(input, &["csv-payments", "transactions.csv"]);
+
+        let full = process_ledger(Ledger::new(), txs.clone());
+
+        // Save after the first dispute of tx 1 is opened, then throw the rest of the run away
+        let mut saved = None;
+
+        process_with_checkpoints(
+            Ledger::new(),
+            txs.clone(),
+            VecDeque::new(),
+            Some(4),
+            |_, _| {},
+            |_, _| {},
+            |ledger, consumed, requeued| {
+                if saved.is_none() {
+                    let mut out = Vec::new();
+                    write_state(&ledger.save_state(consumed, requeued), &mut out)
+                        .expect("state should be written");
+
+                    saved = Some(out);
+                }
+            },
+        );
+
+        let saved = saved.expect("state should have been saved");
+        let state = read_state(&saved[..]).expect("state should be read");
+
+        assert!(state
+            .ref_txs
+            .iter()
+            .any(|record| record.tx.tx_id == 1 && !record.disputed.to_decimal().is_zero()));
+        assert_eq!(state.position, 5);
+        assert_eq!(state.requeued.len(), 1);
+        assert_eq!(state.requeued[0].tx_id, 4);
+
+        // The resumed run is given the same input and skips what was already settled
+        let (ledger, position, requeued) = Ledger::resume(state, Default::default());
+        let input = txs.into_iter().skip(position).collect();
+        let resumed = process_with_checkpoints(
+            ledger,
+            input,
+            requeued,
+            None,
+            |_, _| {},
+            |_, _| {},
+            |_, _, _| {},
+        );
+
+        let balances = |ledger: &Ledger<Balance>| {
+            ledger
+                .iter_sorted()
+                .map(|(id, acc)| {
+                    (
+                        *id,
+                        acc.funds_available.to_decimal(),
+                        acc.funds_held.to_decimal(),
+                        acc.funds_total.to_decimal(),
+                        acc.locked,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(balances(&resumed), balances(&full));
+        assert_eq!(resumed.open_disputes(), full.open_disputes());
+        assert_eq!(resumed.open_disputes(), [4]);
+        assert_eq!(resumed.errors.len(), full.errors.len());
+        assert!(resumed.accounts[&1].locked);
+    }
+}
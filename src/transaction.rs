@@ -1,23 +1,317 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
 use rust_decimal::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
-#[serde(rename_all = "lowercase")]
+use crate::amount::Amount;
+use crate::error::PaymentError;
+
+/// Maps the integer codes used by legacy feeds to transaction types
+pub type TypeCodes = HashMap<u32, TransactionType>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Reversal,
+    Authorize,
+    Capture,
+    Expire,
+}
+
+impl FromStr for TransactionType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TransactionType::ALL
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(r#type, _)| *r#type)
+            .ok_or(())
+    }
+}
+
+impl Serialize for TransactionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+
+        name.parse()
+            .map_err(|_| de::Error::custom(format!("unknown transaction type `{name}`")))
+    }
+}
+
+impl TransactionType {
+    /// Every transaction type with the name used for it in the input and output
+    pub const ALL: [(TransactionType, &'static str); 9] = [
+        (TransactionType::Deposit, "deposit"),
+        (TransactionType::Withdrawal, "withdrawal"),
+        (TransactionType::Dispute, "dispute"),
+        (TransactionType::Resolve, "resolve"),
+        (TransactionType::Chargeback, "chargeback"),
+        (TransactionType::Reversal, "reversal"),
+        (TransactionType::Authorize, "authorize"),
+        (TransactionType::Capture, "capture"),
+        (TransactionType::Expire, "expire"),
+    ];
+
+    /// The name used for this type in the input
+    pub fn name(self) -> &'static str {
+        TransactionType::ALL
+            .iter()
+            .find(|(r#type, _)| *r#type == self)
+            .map(|(_, name)| *name)
+            .expect("every transaction type should be listed")
+    }
+
+    /// Whether transactions of this type are retained so later transactions can reference them
+    pub fn is_referenceable(self) -> bool {
+        matches!(
+            self,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Authorize
+        )
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
-pub struct Transaction {
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct Transaction<A = Decimal> {
     pub r#type: TransactionType,
     #[serde(rename = "client")]
     pub client_id: u16,
     #[serde(rename = "tx")]
     pub tx_id: u32,
-    #[serde(deserialize_with = "csv::invalid_option")]
+    pub amount: Option<A>,
+    /// When the transaction happened, in seconds since the Unix epoch, if the input says
+    #[serde(skip)]
+    pub timestamp: Option<u64>,
+}
+
+/// The `type` field of an input row, either a type name or an integer code from a legacy feed
+#[derive(Debug)]
+pub enum TypeField {
+    Name(TransactionType),
+    Code(u32),
+}
+
+impl<'de> Deserialize<'de> for TypeField {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // JSON input may give codes as numbers rather than strings
+        deserializer.deserialize_any(TypeFieldVisitor)
+    }
+}
+
+struct TypeFieldVisitor;
+
+impl de::Visitor<'_> for TypeFieldVisitor {
+    type Value = TypeField;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a transaction type or an integer type code")
+    }
+
+    fn visit_u64<E: de::Error>(self, code: u64) -> Result<TypeField, E> {
+        u32::try_from(code)
+            .map(TypeField::Code)
+            .map_err(|_| E::custom(format!("type code {code} is out of range")))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<TypeField, E> {
+        if let Ok(code) = value.parse() {
+            return Ok(TypeField::Code(code));
+        }
+
+        value
+            .parse()
+            .map(TypeField::Name)
+            .map_err(|_| E::custom(format!("unknown transaction type `{value}`")))
+    }
+}
+
+/// A transaction as read from the input, tolerating an empty `client` field so the caller can
+/// decide whether to skip the row or abort
+#[derive(Debug, Deserialize)]
+pub struct TransactionRow {
+    pub r#type: TypeField,
+    #[serde(rename = "client")]
+    pub client_id: Option<u16>,
+    #[serde(rename = "tx")]
+    pub tx_id: u32,
+    #[serde(default, deserialize_with = "csv::invalid_option")]
     pub amount: Option<Decimal>,
+    /// Optional in every input version, but rejected when present and not a number
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Only present from input version 2
+    #[serde(default)]
+    pub currency: Option<String>,
+}
+
+impl TransactionRow {
+    /// Returns the transaction, resolving integer type codes through `type_codes`
+    pub fn into_transaction<A: Amount>(
+        self,
+        type_codes: &TypeCodes,
+    ) -> Result<Transaction<A>, PaymentError> {
+        let r#type = match self.r#type {
+            TypeField::Name(r#type) => r#type,
+            TypeField::Code(code) => {
+                *type_codes.get(&code).ok_or(PaymentError::UnknownTypeCode {
+                    tx_id: self.tx_id,
+                    code,
+                })?
+            }
+        };
+
+        Ok(Transaction {
+            r#type,
+            client_id: self
+                .client_id
+                .ok_or(PaymentError::EmptyClient { tx_id: self.tx_id })?,
+            tx_id: self.tx_id,
+            amount: self.amount.map(A::from_decimal),
+            timestamp: self.timestamp,
+        })
+    }
+}
+
+/// Lifecycle of a stored transaction as later transactions reference it
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum TransactionState {
+    Authorized,
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Reversed,
+    Expired,
+}
+
+impl TransactionState {
+    /// Returns the state a referenced transaction moves into when a transaction of `tx_type` is
+    /// applied to it, or `None` if that transition isn't allowed. A chargeback can only be followed
+    /// by a reversal, which is terminal, as is an authorization expiring before it's captured.
+    pub fn transition(self, tx_type: TransactionType) -> Option<TransactionState> {
+        match (self, tx_type) {
+            // A captured authorization behaves like a settled deposit from then on
+            (TransactionState::Authorized, TransactionType::Capture) => {
+                Some(TransactionState::Processed)
+            }
+            (TransactionState::Authorized, TransactionType::Expire) => {
+                Some(TransactionState::Expired)
+            }
+            (
+                TransactionState::Processed
+                | TransactionState::Disputed
+                | TransactionState::Resolved,
+                TransactionType::Dispute,
+            ) => Some(TransactionState::Disputed),
+            (TransactionState::Disputed, TransactionType::Resolve) => {
+                Some(TransactionState::Resolved)
+            }
+            (TransactionState::Disputed, TransactionType::Chargeback) => {
+                Some(TransactionState::ChargedBack)
+            }
+            (TransactionState::ChargedBack, TransactionType::Reversal) => {
+                Some(TransactionState::Reversed)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A deposit, withdrawal or authorization retained so later transactions can reference it
+#[derive(Debug, Clone, Copy)]
+pub struct TransactionRecord<A = Decimal> {
+    pub tx: Transaction<A>,
+    pub state: TransactionState,
+    /// How many disputes of this transaction have been resolved
+    pub dispute_cycles: u32,
+    /// Amount currently held by open disputes of this transaction
+    pub disputed: A,
+    /// Amount taken out of the account by this transaction's chargeback
+    pub charged_back: A,
+}
+
+impl<A: Amount> TransactionRecord<A> {
+    pub fn new(tx: Transaction<A>) -> TransactionRecord<A> {
+        let state = match tx.r#type {
+            TransactionType::Authorize => TransactionState::Authorized,
+            _ => TransactionState::Processed,
+        };
+
+        TransactionRecord {
+            tx,
+            state,
+            dispute_cycles: 0,
+            disputed: A::zero(),
+            charged_back: A::zero(),
+        }
+    }
+}
+
+/// A retained transaction as dumped by `--dump-refs`. The input columns come first so the dump
+/// can still be read as input, followed by its lifecycle so a later run can pick up its disputes.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RefRow {
+    pub r#type: TransactionType,
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    #[serde(rename = "tx")]
+    pub tx_id: u32,
+    pub amount: Option<Decimal>,
+    /// The lifecycle columns are missing from plain input files
+    #[serde(default)]
+    pub state: Option<TransactionState>,
+    #[serde(default)]
+    pub disputed: Option<Decimal>,
+    #[serde(default)]
+    pub charged_back: Option<Decimal>,
+    #[serde(default)]
+    pub dispute_cycles: Option<u32>,
+}
+
+impl RefRow {
+    pub fn new<A: Amount>(record: &TransactionRecord<A>) -> RefRow {
+        RefRow {
+            r#type: record.tx.r#type,
+            client_id: record.tx.client_id,
+            tx_id: record.tx.tx_id,
+            amount: record.tx.amount.map(A::to_decimal),
+            state: Some(record.state),
+            disputed: Some(record.disputed.to_decimal().normalize()),
+            charged_back: Some(record.charged_back.to_decimal().normalize()),
+            dispute_cycles: Some(record.dispute_cycles),
+        }
+    }
+
+    /// Returns the record, starting out unreferenced wherever the lifecycle columns are missing
+    pub fn into_record<A: Amount>(self) -> TransactionRecord<A> {
+        let mut record = TransactionRecord::new(Transaction {
+            r#type: self.r#type,
+            client_id: self.client_id,
+            tx_id: self.tx_id,
+            amount: self.amount.map(A::from_decimal),
+            timestamp: None,
+        });
+
+        if let Some(state) = self.state {
+            record.state = state;
+        }
+
+        record.disputed = A::from_decimal(self.disputed.unwrap_or_default());
+        record.charged_back = A::from_decimal(self.charged_back.unwrap_or_default());
+        record.dispute_cycles = self.dispute_cycles.unwrap_or_default();
+
+        record
+    }
 }